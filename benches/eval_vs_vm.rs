@@ -0,0 +1,45 @@
+// Times Eval against the bytecode Vm on naive recursive fib. Run with
+// `cargo bench`.
+extern crate libhackit_v2;
+
+use libhackit_v2::ast::Ast;
+use libhackit_v2::eval::{Eval, Scope};
+use libhackit_v2::lex::{Lexer, LexerTrait};
+use libhackit_v2::parse::{Parser, ParserTrait};
+use libhackit_v2::vm::Vm;
+use std::time::{Duration, Instant};
+
+const FIB: &str = "(set (name fib) (params n)
+  (body (if (eq (n) 0) 0 (if (eq (n) 1) 1 (add (fib (sub (n) 1)) (fib (sub (n) 2)))))))
+(fib 20)";
+
+const RUNS: u32 = 5;
+
+fn parse(src: &str) -> Ast {
+    let mut parser = Parser::new();
+    for tok in Lexer::new(src).lex().unwrap() {
+        parser.parse_token(tok).unwrap();
+    }
+    parser.finish().unwrap()
+}
+
+fn time<F: FnMut() -> String>(name: &str, mut run: F) -> Duration {
+    let mut best = Duration::from_secs(u64::MAX);
+    let mut result = String::new();
+    for _ in 0..RUNS {
+        let start = Instant::now();
+        result = run();
+        best = best.min(start.elapsed());
+    }
+    println!("{:<6} {:>10.3} ms  (fib 20) = {}", name, best.as_secs_f64() * 1000.0, result);
+    best
+}
+
+fn main() {
+    let tree = parse(FIB);
+    let eval = time("eval", || {
+        Eval::new(tree.clone()).eval_with_scope(Scope::new_root()).1.unwrap().to_string()
+    });
+    let vm = time("vm", || Vm::new().run(&tree).unwrap().to_string());
+    println!("vm is {:.1}x faster", eval.as_secs_f64() / vm.as_secs_f64());
+}
@@ -0,0 +1,143 @@
+// Times Scope::find_func on deep scope chains and on scopes with many
+// definitions, next to the sorted Vec of string keyed functions that Scope
+// used to be, and deep recursion in Eval against the Vm. Run with
+// `cargo bench`.
+extern crate libhackit_v2;
+
+use libhackit_v2::ast::Ast;
+use libhackit_v2::eval::{Eval, Func, Scope};
+use libhackit_v2::expr::Expr;
+use libhackit_v2::intern::Symbol;
+use libhackit_v2::lex::{Lexer, LexerTrait};
+use libhackit_v2::parse::{Parser, ParserTrait};
+use libhackit_v2::token::Span;
+use libhackit_v2::value::Value;
+use libhackit_v2::vm::Vm;
+use std::time::{Duration, Instant};
+
+const LOOKUPS: usize = 100_000;
+
+// The old layout, kept here only to compare against.
+struct SortedScope {
+    parent: Option<Box<SortedScope>>,
+    funcs: Vec<(String, Expr)>
+}
+
+impl SortedScope {
+    fn insert(&mut self, name: String) {
+        if let Err(index) = self.funcs.binary_search_by(|func| func.0.cmp(&name)) {
+            self.funcs.insert(index, (name, body()));
+        }
+    }
+
+    fn find(&mut self, name: &str) -> Option<&mut Expr> {
+        match self.funcs.binary_search_by(|func| func.0.as_str().cmp(name)) {
+            Ok(index) => Some(&mut self.funcs[index].1),
+            Err(_) => match self.parent {
+                Some(ref mut parent) => parent.find(name),
+                None => None
+            }
+        }
+    }
+}
+
+fn body() -> Expr {
+    Expr::Literal(Value::Nil, Span::default())
+}
+
+fn name(i: usize) -> String {
+    format!("function_name_{}", i)
+}
+
+fn time<F: FnMut() -> usize>(mut run: F) -> Duration {
+    let start = Instant::now();
+    let mut found = 0;
+    for _ in 0..LOOKUPS {
+        found += run();
+    }
+    assert_eq!(found, LOOKUPS);
+    start.elapsed()
+}
+
+fn report(case: &str, sorted: Duration, hashed: Duration) {
+    let per = |total: Duration| total.as_secs_f64() * 1e9 / LOOKUPS as f64;
+    println!("{:<28} sorted vec {:>9.1} ns   hash map {:>9.1} ns", case, per(sorted), per(hashed));
+}
+
+// A name defined in the root, looked up from depth scopes below it, each
+// holding one definition of its own.
+fn deep_chain(depth: usize) {
+    let mut sorted = SortedScope { parent: None, funcs: Vec::new() };
+    let mut hashed = Scope::new_root();
+    sorted.insert(name(0));
+    hashed.insert_func_no_search(Func::new(Symbol::intern(&name(0)), Vec::new(), body()));
+    for i in 1..depth + 1 {
+        sorted = SortedScope { parent: Some(Box::new(sorted)), funcs: Vec::new() };
+        sorted.insert(name(i));
+        hashed = Scope::new(hashed);
+        hashed.insert_func_no_search(Func::new(Symbol::intern(&name(i)), Vec::new(), body()));
+    }
+    let target = name(0);
+    let symbol = Symbol::intern(&target);
+    let sorted_time = time(|| sorted.find(&target).is_some() as usize);
+    let hashed_time = time(|| hashed.find_func(symbol).is_some() as usize);
+    report(&format!("chain of depth {}", depth), sorted_time, hashed_time);
+}
+
+// Lookups spread over count definitions in a single scope.
+fn many_definitions(count: usize) {
+    let mut sorted = SortedScope { parent: None, funcs: Vec::new() };
+    let mut hashed = Scope::new_root();
+    let names: Vec<String> = (0..count).map(name).collect();
+    let symbols: Vec<Symbol> = names.iter().map(|name| Symbol::intern(name)).collect();
+    for (name, symbol) in names.iter().zip(symbols.iter()) {
+        sorted.insert(name.clone());
+        hashed.insert_func_no_search(Func::new(*symbol, Vec::new(), body()));
+    }
+    let mut i = 0;
+    let sorted_time = time(|| {
+        i = (i + 7919) % count;
+        sorted.find(&names[i]).is_some() as usize
+    });
+    let hashed_time = time(|| {
+        i = (i + 7919) % count;
+        hashed.find_func(symbols[i]).is_some() as usize
+    });
+    report(&format!("{} definitions", count), sorted_time, hashed_time);
+}
+
+fn parse(src: &str) -> Ast {
+    let mut parser = Parser::new();
+    for tok in Lexer::new(src).lex().unwrap() {
+        parser.parse_token(tok).unwrap();
+    }
+    parser.finish().unwrap()
+}
+
+// A root level function calling itself depth deep, so every lookup of it
+// and of the builtins it calls happens under depth open calls. Lookups that
+// walk the calls make this grow with the square of the depth.
+fn recursion(depth: usize) {
+    let src = format!("(set (name down) (params n) (body (if (eq (n) 0) 0 (down (sub (n) 1))))) (down {})", depth);
+    let tree = parse(&src);
+    let start = Instant::now();
+    Eval::new(tree.clone()).eval_with_scope(Scope::new_root()).1.unwrap();
+    let eval = start.elapsed();
+    let start = Instant::now();
+    Vm::new().run(&tree).unwrap();
+    let vm = start.elapsed();
+    println!("{:<28} eval {:>12.3} ms   vm {:>9.3} ms", format!("recursion {} deep", depth),
+             eval.as_secs_f64() * 1000.0, vm.as_secs_f64() * 1000.0);
+}
+
+fn main() {
+    for depth in [1, 16, 256].iter() {
+        deep_chain(*depth);
+    }
+    for count in [16, 1024, 65536].iter() {
+        many_definitions(*count);
+    }
+    for depth in [2000, 4000, 8000].iter() {
+        recursion(*depth);
+    }
+}
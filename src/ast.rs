@@ -1,5 +1,10 @@
+use std::fmt;
+use std::mem;
+use std::slice;
 use token::{Token, Type};
 
+pub const DEFAULT_WIDTH: usize = 80;
+
 #[derive(Clone, Eq, PartialEq, Ord, PartialOrd)]
 pub struct Ast {
     pub node_val: Option<Token>,
@@ -14,6 +19,30 @@ pub trait AstTrait {
     fn child_count(&self) -> usize;
     fn dump_children(&mut self) -> Vec<Ast>;
     fn clone_children(&self) -> Vec<Ast>;
+    fn child(&self, index: usize) -> Option<&Ast>;
+    fn child_mut(&mut self, index: usize) -> Option<&mut Ast>;
+    fn children(&self) -> &[Ast];
+    fn iter(&self) -> slice::Iter<'_, Ast>;
+    fn iter_mut(&mut self) -> slice::IterMut<'_, Ast>;
+}
+
+// Walks a tree in pre-order through enter and post-order through leave.
+// Returning false from enter skips the node's children, though leave is
+// still called for it.
+pub trait Visitor {
+    fn enter(&mut self, _ast: &Ast) -> bool {
+        true
+    }
+
+    fn leave(&mut self, _ast: &Ast) {}
+}
+
+pub trait VisitorMut {
+    fn enter(&mut self, _ast: &mut Ast) -> bool {
+        true
+    }
+
+    fn leave(&mut self, _ast: &mut Ast) {}
 }
 
 impl AstTrait for Ast {
@@ -46,14 +75,32 @@ impl AstTrait for Ast {
     }
 
     fn dump_children(&mut self) -> Vec<Ast> {
-        let children = self.child_nodes.clone();
-        self.child_nodes.clear();
-        children
+        mem::take(&mut self.child_nodes)
     }
 
     fn clone_children(&self) -> Vec<Ast> {
         self.child_nodes.clone()
     }
+
+    fn child(&self, index: usize) -> Option<&Ast> {
+        self.child_nodes.get(index)
+    }
+
+    fn child_mut(&mut self, index: usize) -> Option<&mut Ast> {
+        self.child_nodes.get_mut(index)
+    }
+
+    fn children(&self) -> &[Ast] {
+        &self.child_nodes
+    }
+
+    fn iter(&self) -> slice::Iter<'_, Ast> {
+        self.child_nodes.iter()
+    }
+
+    fn iter_mut(&mut self) -> slice::IterMut<'_, Ast> {
+        self.child_nodes.iter_mut()
+    }
 }
 
 impl Ast {
@@ -71,22 +118,134 @@ impl Ast {
         }
     }
 
-    pub fn is_function(&mut self) -> bool {
-        match self.get_child(0) {
-            Some(child) => {
-                let result = match child.node_val {
-                    Some(ref tok) => {
-                        match tok.tok_type {
-                            Type::Func => true,
-                            _ => false
-                        }
-                    },
-                    None => false
-                };
-                self.insert_child(child, 0);
-                result
-            },
+    pub fn is_function(&self) -> bool {
+        match self.child(0).and_then(|child| child.node_val.as_ref()) {
+            Some(tok) => tok.tok_type == Type::Func,
             None => false
         }
     }
+
+    pub fn walk<V: Visitor>(&self, visitor: &mut V) {
+        if visitor.enter(self) {
+            for child in self.child_nodes.iter() {
+                child.walk(visitor);
+            }
+        }
+        visitor.leave(self);
+    }
+
+    pub fn walk_mut<V: VisitorMut>(&mut self, visitor: &mut V) {
+        if visitor.enter(self) {
+            for child in self.child_nodes.iter_mut() {
+                child.walk_mut(visitor);
+            }
+        }
+        visitor.leave(self);
+    }
+}
+
+impl fmt::Display for Ast {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", pretty(self, DEFAULT_WIDTH))
+    }
+}
+
+// Renders the tree as source. Forms that fit in width stay on one line,
+// longer ones put each argument on its own indented line. The root holds
+// the top level forms and puts each on its own line.
+pub fn pretty(ast: &Ast, width: usize) -> String {
+    let mut pretty = String::new();
+    match ast.node_val {
+        Some(_) => pretty_node(ast, 0, width, &mut pretty),
+        None => {
+            for (i, child) in ast.child_nodes.iter().enumerate() {
+                if i > 0 {
+                    pretty.push('\n');
+                }
+                pretty_node(child, 0, width, &mut pretty);
+            }
+        }
+    }
+    pretty
+}
+
+fn flat(ast: &Ast, out: &mut String) {
+    if let Some(ref tok) = ast.node_val {
+        out.push_str(&tok.to_source());
+        match tok.tok_type {
+            Type::Oparen | Type::OpenList => {
+                for (i, child) in ast.child_nodes.iter().enumerate() {
+                    if i > 0 {
+                        out.push(' ');
+                    }
+                    flat(child, out);
+                }
+                out.push_str(tok.tok_type.closing_lexeme());
+            },
+            _ => ()
+        }
+    }
+}
+
+fn pretty_node(ast: &Ast, indent: usize, width: usize, out: &mut String) {
+    let mut line = String::new();
+    flat(ast, &mut line);
+    let tok = match ast.node_val {
+        Some(ref tok) if indent + line.len() > width && ast.child_nodes.len() >= 2 => tok,
+        _ => {
+            out.push_str(&line);
+            return;
+        }
+    };
+    out.push_str(&tok.to_source());
+    let child_indent = indent + 2;
+    for (i, child) in ast.child_nodes.iter().enumerate() {
+        if i > 0 {
+            out.push('\n');
+            out.push_str(&" ".repeat(child_indent));
+        }
+        pretty_node(child, child_indent, width, out);
+    }
+    out.push_str(tok.tok_type.closing_lexeme());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lex::Lexer;
+    use parse::{Parser, ParserTrait};
+
+    const SAMPLES: &[&str] = &[
+        "(set (name fib) (params n)\n  (body (if (eq (n) 0) 0 (if (eq (n) 1) 1 (add (fib (sub (n) 1)) (fib (sub (n) 2)))))))\n(fib 20)",
+        "(println \"a \\\"quoted\\\" \\\\ word\\n\\ton two lines\" <1 -2.5e3 0x1f <> <\"é\" (f)>>)",
+        "(try (throw <1 2>) (catch (params e) (body (cond ((eq (e) 1) \"one\") (else (e))))) (finally (println \"done\")))",
+        "(f)\n(g 1)\n(h <>)"
+    ];
+
+    fn parse(src: &str) -> Ast {
+        let mut parser = Parser::new();
+        for tok in Lexer::new(src) {
+            parser.parse_token(tok.unwrap()).unwrap();
+        }
+        parser.finish().unwrap()
+    }
+
+    #[test]
+    fn pretty_output_parses_back_to_the_same_tree() {
+        for src in SAMPLES.iter() {
+            let ast = parse(src);
+            for &width in [0, 8, 20, 40, DEFAULT_WIDTH, 1000].iter() {
+                let pretty = pretty(&ast, width);
+                assert!(parse(&pretty) == ast, "width {}:\n{}", width, pretty);
+            }
+        }
+    }
+
+    #[test]
+    fn pretty_keeps_forms_within_the_width_on_one_line() {
+        let ast = parse(SAMPLES[0]);
+        assert_eq!(pretty(&ast, 1000).lines().count(), 2);
+        assert!(pretty(&ast, 20).lines().count() > 2);
+        assert_eq!(ast.to_string(), pretty(&ast, DEFAULT_WIDTH));
+    }
 }
@@ -0,0 +1,470 @@
+extern crate libhackit_v2;
+
+use libhackit_v2::ast::DEFAULT_WIDTH;
+use libhackit_v2::config::InterpreterConfig;
+use libhackit_v2::debug::{Breakpoint, Breakpoints, Debugger, Stop};
+use libhackit_v2::diagnostics::{Diagnostic, Render, Style};
+use libhackit_v2::eval::Func;
+use libhackit_v2::expr::Expr;
+use libhackit_v2::format;
+use libhackit_v2::intern::Symbol;
+use libhackit_v2::lex::Lexer;
+use libhackit_v2::lint;
+use libhackit_v2::parse::{Parser, ParserTrait};
+use libhackit_v2::profile::Profiler;
+use libhackit_v2::token::Span;
+use libhackit_v2::trace::TreeTracer;
+use libhackit_v2::{Error, Interpreter};
+use std::cell::RefCell;
+use std::env;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::process;
+use std::rc::Rc;
+
+const USAGE: &str = "usage: hackit fmt [--check] [--width N] [FILE...]
+       hackit lint [--pure] FILE...
+       hackit repl [--pure]
+       hackit run [--pure] [--profile] [--folded OUT] FILE";
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let code = match args.first().map(|arg| arg.as_str()) {
+        Some("fmt") => fmt(&args[1..]),
+        Some("lint") => lint(&args[1..]),
+        Some("repl") => repl(&args[1..]),
+        Some("run") => run(&args[1..]),
+        _ => {
+            eprintln!("{}", USAGE);
+            2
+        }
+    };
+    process::exit(code);
+}
+
+fn report(err: &Error, file_name: &str, source: &str) {
+    let diag = match *err {
+        Error::Lex(ref err) => Diagnostic::from(err),
+        Error::Parse(ref err) => Diagnostic::from(err),
+        Error::Runtime(ref err) => Diagnostic::from(err),
+        Error::Io(ref err) => Diagnostic::error(err.to_string(), None)
+    };
+    eprint!("{}", diag.render(file_name, source, Style::Plain));
+}
+
+// Formats each file in place, or with --check only lists the files that
+// would change. With no files, formats stdin to stdout.
+fn fmt(args: &[String]) -> i32 {
+    let mut check = false;
+    let mut width = DEFAULT_WIDTH;
+    let mut files = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--check" => check = true,
+            "--width" => {
+                match args.next().and_then(|w| w.parse().ok()) {
+                    Some(w) => width = w,
+                    None => {
+                        eprintln!("{}", USAGE);
+                        return 2;
+                    }
+                }
+            },
+            _ => files.push(arg.clone())
+        }
+    }
+
+    if files.is_empty() {
+        let mut source = String::new();
+        if let Err(err) = io::stdin().read_to_string(&mut source) {
+            eprintln!("error: {}", err);
+            return 2;
+        }
+        return match format::format_source(&source, width) {
+            Ok(formatted) => {
+                if check {
+                    (formatted != source) as i32
+                } else {
+                    print!("{}", formatted);
+                    io::stdout().flush().map(|_| 0).unwrap_or(2)
+                }
+            },
+            Err(err) => {
+                report(&err, "<stdin>", &source);
+                2
+            }
+        };
+    }
+
+    let mut code = 0;
+    for file in files.iter() {
+        let source = match fs::read_to_string(file) {
+            Ok(source) => source,
+            Err(err) => {
+                eprintln!("error: {}: {}", file, err);
+                code = 2;
+                continue;
+            }
+        };
+        match format::format_source(&source, width) {
+            Ok(ref formatted) if *formatted == source => (),
+            Ok(formatted) => {
+                if check {
+                    println!("{}", file);
+                    if code == 0 {
+                        code = 1;
+                    }
+                } else if let Err(err) = fs::write(file, formatted) {
+                    eprintln!("error: {}: {}", file, err);
+                    code = 2;
+                }
+            },
+            Err(err) => {
+                report(&err, file, &source);
+                code = 2;
+            }
+        }
+    }
+    code
+}
+
+// Reports every parse error and lint in each file. Exits with 1 if any of
+// them are errors. With --pure, builtins outside the pure profile are
+// reported as undefined.
+fn lint(args: &[String]) -> i32 {
+    let mut config = InterpreterConfig::trusted();
+    let mut files = Vec::new();
+    for arg in args.iter() {
+        match arg.as_str() {
+            "--pure" => config = InterpreterConfig::new(),
+            _ => files.push(arg.clone())
+        }
+    }
+    if files.is_empty() {
+        eprintln!("{}", USAGE);
+        return 2;
+    }
+    let mut code = 0;
+    for file in files.iter() {
+        let source = match fs::read_to_string(file) {
+            Ok(source) => source,
+            Err(err) => {
+                eprintln!("error: {}: {}", file, err);
+                code = 2;
+                continue;
+            }
+        };
+        let mut parser = Parser::new_recovering();
+        for tok in Lexer::new(&source) {
+            match tok {
+                Ok(tok) => {
+                    let _ = parser.parse_token(tok);
+                },
+                Err(err) => {
+                    report(&Error::Lex(err), file, &source);
+                    code = 1;
+                    break;
+                }
+            }
+        }
+        let (tree, errors) = parser.finish_recovering();
+        for err in errors.into_iter() {
+            report(&Error::Parse(err), file, &source);
+            code = 1;
+        }
+        for found in lint::lint_with_config(&tree, &config).iter() {
+            eprint!("{}", Diagnostic::from(found).render(file, &source, Style::Plain));
+            if found.is_error() {
+                code = 1;
+            }
+        }
+    }
+    code
+}
+
+// Runs a script. With --profile, prints where its steps went afterwards,
+// and with --folded writes them as folded stacks for flamegraph tools.
+fn run(args: &[String]) -> i32 {
+    let mut config = InterpreterConfig::trusted();
+    let mut profile = false;
+    let mut folded = None;
+    let mut files = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--pure" => config = InterpreterConfig::new(),
+            "--profile" => profile = true,
+            "--folded" => {
+                match args.next() {
+                    Some(out) => folded = Some(out.clone()),
+                    None => {
+                        eprintln!("{}", USAGE);
+                        return 2;
+                    }
+                }
+            },
+            _ => files.push(arg.clone())
+        }
+    }
+    if files.len() != 1 {
+        eprintln!("{}", USAGE);
+        return 2;
+    }
+    let file = &files[0];
+    let source = match fs::read_to_string(file) {
+        Ok(source) => source,
+        Err(err) => {
+            eprintln!("error: {}: {}", file, err);
+            return 2;
+        }
+    };
+    let mut interpreter = Interpreter::with_config(config);
+    if profile || folded.is_some() {
+        interpreter.set_profiler(Some(Profiler::new()));
+    }
+    let code = match interpreter.eval_str(&source) {
+        Ok(_) => 0,
+        Err(err) => {
+            report(&err, file, &source);
+            1
+        }
+    };
+    if let Some(profiler) = interpreter.take_profiler() {
+        if profile {
+            eprint!("{}", profiler.report());
+        }
+        if let Some(out) = folded {
+            if let Err(err) = fs::write(&out, profiler.folded()) {
+                eprintln!("error: {}: {}", out, err);
+                return 2;
+            }
+        }
+    }
+    code
+}
+
+const REPL_HELP: &str = "  :debug            run what follows under the debugger, again to stop
+  :load FILE        run a file
+  :trace            print each call as it happens, again to stop
+  :break LINE|NAME  stop at a line or on calls to a function
+  :delete ID        remove a breakpoint
+  :breakpoints      list breakpoints
+  :quit";
+
+const DEBUG_HELP: &str = "  step, s           stop before the next form
+  next, n           the same, running over calls
+  out, o            stop once the current call returns
+  continue, c       run to a breakpoint or the end
+  break, b LINE|NAME
+  delete ID
+  backtrace, bt     the calls in progress
+  scope             the names each scope defines
+  pending           what is left to evaluate
+  quit, q           stop debugging, keeping what was defined";
+
+struct Repl {
+    interpreter: Interpreter,
+    breakpoints: Breakpoints,
+    debug: bool,
+    trace: bool
+}
+
+// Reads forms a line at a time and prints their values. Definitions carry
+// over from line to line.
+fn repl(args: &[String]) -> i32 {
+    let config = match args.first().map(|arg| arg.as_str()) {
+        None => InterpreterConfig::trusted(),
+        Some("--pure") => InterpreterConfig::new(),
+        Some(_) => {
+            eprintln!("{}", USAGE);
+            return 2;
+        }
+    };
+    let mut repl = Repl {
+        interpreter: Interpreter::with_config(config),
+        breakpoints: Breakpoints::new(),
+        debug: false,
+        trace: false
+    };
+    while let Some(line) = read_line(match repl.debug {
+        true => "debug> ",
+        false => "> "
+    }) {
+        let line = line.trim();
+        let (command, arg) = match line.find(' ') {
+            Some(at) => (&line[..at], line[at + 1..].trim()),
+            None => (line, "")
+        };
+        match command {
+            "" => (),
+            ":quit" => break,
+            ":help" => println!("{}", REPL_HELP),
+            ":debug" => repl.debug = !repl.debug,
+            ":trace" => {
+                repl.trace = !repl.trace;
+                repl.interpreter.set_tracer(match repl.trace {
+                    true => Some(Rc::new(RefCell::new(TreeTracer::new(io::stderr())))),
+                    false => None
+                });
+            },
+            ":break" => add_breakpoint(&mut repl.breakpoints, arg),
+            ":delete" => delete_breakpoint(&mut repl.breakpoints, arg),
+            ":breakpoints" => {
+                for (id, breakpoint) in repl.breakpoints.iter() {
+                    println!("{}: {}", id, show_breakpoint(breakpoint));
+                }
+            },
+            ":load" => {
+                match fs::read_to_string(arg) {
+                    Ok(source) => repl.run(&source, arg),
+                    Err(err) => eprintln!("error: {}: {}", arg, err)
+                }
+            },
+            _ if command.starts_with(':') => eprintln!("unknown command `{}`, try :help", command),
+            _ => repl.run(line, "<repl>")
+        }
+    }
+    0
+}
+
+impl Repl {
+    fn run(&mut self, source: &str, file_name: &str) {
+        if !self.debug {
+            match self.interpreter.eval_str(source) {
+                Ok(val) => println!("{}", val),
+                Err(err) => report(&err, file_name, source)
+            }
+            return;
+        }
+        let mut debugger = match self.interpreter.debug_str(source) {
+            Ok(debugger) => debugger,
+            Err(err) => {
+                report(&err, file_name, source);
+                return;
+            }
+        };
+        debugger.breakpoints = mem::take(&mut self.breakpoints);
+        let mut stop = debugger.step_into();
+        loop {
+            match stop {
+                Stop::Finished(Ok(val)) => {
+                    println!("{}", val);
+                    break;
+                },
+                Stop::Finished(Err(err)) => {
+                    report(&Error::Runtime(err), file_name, source);
+                    break;
+                },
+                Stop::Step(span) => show_location(span, file_name, source),
+                Stop::Breakpoint(id, span) => {
+                    println!("breakpoint {}", id);
+                    show_location(span, file_name, source);
+                }
+            }
+            stop = match debug_command(&mut debugger) {
+                Some(stop) => stop,
+                None => break
+            };
+        }
+        self.breakpoints = mem::take(&mut debugger.breakpoints);
+        self.interpreter.end_debug(debugger);
+    }
+}
+
+// Runs debugger commands until one of them moves evaluation on, or returns
+// None to stop debugging.
+fn debug_command(debugger: &mut Debugger) -> Option<Stop> {
+    loop {
+        let line = read_line("(debug) ")?;
+        let line = line.trim();
+        let (command, arg) = match line.find(' ') {
+            Some(at) => (&line[..at], line[at + 1..].trim()),
+            None => (line, "")
+        };
+        match command {
+            "step" | "s" => return Some(debugger.step_into()),
+            "next" | "n" => return Some(debugger.step_over()),
+            "out" | "o" => return Some(debugger.step_out()),
+            "continue" | "c" => return Some(debugger.resume()),
+            "quit" | "q" => return None,
+            "break" | "b" => add_breakpoint(&mut debugger.breakpoints, arg),
+            "delete" => delete_breakpoint(&mut debugger.breakpoints, arg),
+            "backtrace" | "bt" => {
+                for (i, call) in debugger.backtrace().iter().enumerate().rev() {
+                    println!("{:>3}: {} called at {}:{}", i, call.name, call.span.line, call.span.col);
+                }
+                println!("  -: top level");
+            },
+            "scope" => {
+                let scope = debugger.scope();
+                for level in (0..scope.depth() + 1).rev() {
+                    println!("scope {}:", level);
+                    for func in scope.funcs_at(level) {
+                        println!("  {}", show_func(func));
+                    }
+                }
+            },
+            "pending" => {
+                for task in debugger.pending().iter() {
+                    println!("  {}", task);
+                }
+            },
+            _ => println!("{}", DEBUG_HELP)
+        }
+    }
+}
+
+fn read_line(prompt: &str) -> Option<String> {
+    print!("{}", prompt);
+    io::stdout().flush().ok()?;
+    let mut line = String::new();
+    match io::stdin().read_line(&mut line) {
+        Ok(0) | Err(_) => None,
+        Ok(_) => Some(line)
+    }
+}
+
+fn add_breakpoint(breakpoints: &mut Breakpoints, arg: &str) {
+    let breakpoint = match arg.parse() {
+        Ok(line) => Breakpoint::Line(line),
+        Err(_) if !arg.is_empty() => Breakpoint::Function(Symbol::intern(arg)),
+        Err(_) => {
+            eprintln!("break needs a line or a function name");
+            return;
+        }
+    };
+    let text = show_breakpoint(&breakpoint);
+    println!("breakpoint {} at {}", breakpoints.add(breakpoint), text);
+}
+
+fn delete_breakpoint(breakpoints: &mut Breakpoints, arg: &str) {
+    match arg.parse().ok().and_then(|id| breakpoints.remove(id)) {
+        Some(_) => (),
+        None => eprintln!("no breakpoint `{}`", arg)
+    }
+}
+
+fn show_breakpoint(breakpoint: &Breakpoint) -> String {
+    match *breakpoint {
+        Breakpoint::Line(line) => format!("line {}", line),
+        Breakpoint::Function(name) => format!("function {}", name)
+    }
+}
+
+fn show_location(span: Span, file_name: &str, source: &str) {
+    let text = source.lines().nth(span.line.saturating_sub(1)).unwrap_or("");
+    println!("{}:{}:{}: {}", file_name, span.line, span.col, text.trim());
+}
+
+// A let shows its value, a function its params.
+fn show_func(func: &Func) -> String {
+    match (func.params.is_empty(), &func.body) {
+        (true, Expr::Literal(val, _)) => format!("{} = {}", func.get_name(), val),
+        _ => {
+            let params: Vec<String> = func.params.iter().map(|param| param.to_string()).collect();
+            format!("{}({})", func.get_name(), params.join(" "))
+        }
+    }
+}
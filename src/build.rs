@@ -0,0 +1,194 @@
+use ast::{Ast, AstTrait};
+use token::{Token, Type};
+
+// Anything that can stand in a form built from Rust. Strings become string
+// literals, use sym for a name.
+pub trait IntoAst {
+    fn into_ast(self) -> Ast;
+}
+
+impl IntoAst for Ast {
+    fn into_ast(self) -> Ast {
+        self
+    }
+}
+
+impl IntoAst for Token {
+    fn into_ast(self) -> Ast {
+        Ast::new(self)
+    }
+}
+
+impl IntoAst for FormBuilder {
+    fn into_ast(self) -> Ast {
+        self.build()
+    }
+}
+
+impl IntoAst for i64 {
+    fn into_ast(self) -> Ast {
+        Ast::new(Token::new_preset(self.to_string(), Type::Number))
+    }
+}
+
+impl IntoAst for i32 {
+    fn into_ast(self) -> Ast {
+        (self as i64).into_ast()
+    }
+}
+
+impl IntoAst for f64 {
+    fn into_ast(self) -> Ast {
+        // Debug keeps the ".0" so the number lowers back into a float.
+        Ast::new(Token::new_preset(format!("{:?}", self), Type::Number))
+    }
+}
+
+impl IntoAst for bool {
+    fn into_ast(self) -> Ast {
+        Ast::new(Token::new_preset(self.to_string(), Type::StrType))
+    }
+}
+
+impl IntoAst for &str {
+    fn into_ast(self) -> Ast {
+        Ast::new(Token::new_preset(self.to_string(), Type::StrType))
+    }
+}
+
+impl IntoAst for String {
+    fn into_ast(self) -> Ast {
+        Ast::new(Token::new_preset(self, Type::StrType))
+    }
+}
+
+pub fn sym(name: &str) -> Ast {
+    Ast::new(Token::new_preset(name.to_string(), Type::Func))
+}
+
+pub fn form(children: Vec<Ast>) -> Ast {
+    with_children(Token::new_preset("(".to_string(), Type::Oparen), children)
+}
+
+pub fn list(items: Vec<Ast>) -> Ast {
+    with_children(Token::new_preset("<".to_string(), Type::OpenList), items)
+}
+
+// A root holding top level forms, the same shape the parser produces.
+pub fn program(forms: Vec<Ast>) -> Ast {
+    let mut root = Ast::new_null();
+    for form in forms.into_iter() {
+        root.push_child(form);
+    }
+    root
+}
+
+fn with_children(tok: Token, children: Vec<Ast>) -> Ast {
+    let mut ast = Ast::new(tok);
+    for child in children.into_iter() {
+        ast.push_child(child);
+    }
+    ast
+}
+
+// Builds a form one argument at a time, for code that decides the shape as
+// it goes. FormBuilder::call("add").arg(1).arg(sym("x")) is (add 1 x).
+pub struct FormBuilder {
+    ast: Ast
+}
+
+impl FormBuilder {
+    pub fn call(head: &str) -> Self {
+        FormBuilder {
+            ast: form(vec![sym(head)])
+        }
+    }
+
+    pub fn list() -> Self {
+        FormBuilder {
+            ast: list(Vec::new())
+        }
+    }
+
+    pub fn arg<T: IntoAst>(mut self, arg: T) -> Self {
+        self.ast.push_child(arg.into_ast());
+        self
+    }
+
+    pub fn args<T: IntoAst, I: IntoIterator<Item = T>>(mut self, args: I) -> Self {
+        for arg in args.into_iter() {
+            self.ast.push_child(arg.into_ast());
+        }
+        self
+    }
+
+    pub fn build(self) -> Ast {
+        self.ast
+    }
+}
+
+// (set (name name) (params ...) (body body))
+pub fn set<T: IntoAst>(name: &str, params: &[&str], body: T) -> Ast {
+    FormBuilder::call("set")
+        .arg(FormBuilder::call("name").arg(sym(name)))
+        .arg(FormBuilder::call("params").args(params.iter().map(|param| sym(param))))
+        .arg(FormBuilder::call("body").arg(body))
+        .build()
+}
+
+// (let (name name) (body body))
+pub fn let_form<T: IntoAst>(name: &str, body: T) -> Ast {
+    FormBuilder::call("let")
+        .arg(FormBuilder::call("name").arg(sym(name)))
+        .arg(FormBuilder::call("body").arg(body))
+        .build()
+}
+
+// (lambda (params ...) (body body))
+pub fn lambda<T: IntoAst>(params: &[&str], body: T) -> Ast {
+    FormBuilder::call("lambda")
+        .arg(FormBuilder::call("params").args(params.iter().map(|param| sym(param))))
+        .arg(FormBuilder::call("body").arg(body))
+        .build()
+}
+
+// Writes Hackit inline. Parens are forms, square brackets are lists (the
+// angle brackets of the source syntax do not nest as Rust tokens), idents
+// are names and literals go through IntoAst. Braces splice in any Rust
+// expression whose type is IntoAst. More than one form gives a program.
+//
+//     hackit!((set (name double) (params x) (body (mult (x) 2))))
+//     hackit!((println {greeting}) (println [1 2 -3]))
+#[macro_export]
+macro_rules! hackit {
+    (@node ( $($inner:tt)* )) => {
+        $crate::build::form($crate::hackit!(@all [] $($inner)*))
+    };
+    (@node [ $($inner:tt)* ]) => {
+        $crate::build::list($crate::hackit!(@all [] $($inner)*))
+    };
+    (@node { $splice:expr }) => {
+        $crate::build::IntoAst::into_ast($splice)
+    };
+    (@node $name:ident) => {
+        $crate::build::sym(stringify!($name))
+    };
+    (@node $lit:literal) => {
+        $crate::build::IntoAst::into_ast($lit)
+    };
+    (@all [ $($done:expr,)* ]) => {
+        vec![$($done),*]
+    };
+    (@all [ $($done:expr,)* ] - $lit:literal $($rest:tt)*) => {
+        $crate::hackit!(@all [ $($done,)* $crate::build::IntoAst::into_ast(-$lit), ] $($rest)*)
+    };
+    (@all [ $($done:expr,)* ] $next:tt $($rest:tt)*) => {
+        $crate::hackit!(@all [ $($done,)* $crate::hackit!(@node $next), ] $($rest)*)
+    };
+    ($single:tt) => {
+        $crate::hackit!(@node $single)
+    };
+    ($($forms:tt)+) => {
+        $crate::build::program($crate::hackit!(@all [] $($forms)+))
+    };
+}
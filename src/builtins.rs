@@ -1,426 +1,240 @@
-use ast::{Ast, AstTrait};
-use token::{Type, Token, TokenTrait};
-use eval::{Scope, Func};
-use std::borrow::Borrow;
-
-pub enum EvalResult<T: AstTrait> {
-    Push(T),
-    Insert(T),
-    Ignore,
-    Error
+use config::Capability;
+use std::cell::Cell;
+use std::env;
+use std::fs;
+use std::io::{self, BufRead};
+use std::time::{SystemTime, UNIX_EPOCH};
+use token;
+use value::Value;
+
+pub const BUILTINS: &[&str] = &["print", "println", "readline", "add", "sub", "mult", "div", "eq", "readfile",
+                                "writefile", "getenv", "now", "random", "randint", "throw"];
+
+// Forms lowered into their own Expr variants rather than called.
+pub const SPECIAL_FORMS: &[&str] = &["set", "let", "lambda", "if", "cond", "else", "when", "unless", "do",
+                                     "name", "params", "body", "try", "catch", "finally"];
+
+pub fn is_builtin(name: &str) -> bool {
+    BUILTINS.contains(&name) || SPECIAL_FORMS.contains(&name)
+}
+
+// The capability a script needs to call the builtin. Pure builtins need
+// none.
+pub fn capability(name: &str) -> Option<Capability> {
+    match name {
+        "print" | "println" | "readline" => Some(Capability::ConsoleIo),
+        "readfile" | "writefile" => Some(Capability::FileIo),
+        "getenv" => Some(Capability::Env),
+        "now" => Some(Capability::Time),
+        "random" | "randint" => Some(Capability::Random),
+        _ => None
+    }
+}
+
+// How a builtin call failed.
+#[derive(Clone, Debug, PartialEq)]
+pub enum BuiltinError {
+    // A runtime error with the message.
+    Failed(String),
+    // The script threw the value, for a try to catch.
+    Thrown(Value)
+}
+
+// Runs the builtin called name, or returns None if there is no such builtin.
+// Capabilities are not checked here, see InterpreterConfig::call_builtin.
+pub fn call_builtin(name: &str, args: &[Value]) -> Option<Result<Value, BuiltinError>> {
+    let result = match name {
+        "print" => print(args),
+        "println" => println(args),
+        "readline" => arity(name, args, 0).and_then(|_| readline()),
+        "add" => arithmetic(name, args, i64::checked_add, |a, b| a + b),
+        "sub" => arithmetic(name, args, i64::checked_sub, |a, b| a - b),
+        "mult" => arithmetic(name, args, i64::checked_mul, |a, b| a * b),
+        "div" => div(args),
+        "eq" => eq(args),
+        "readfile" => arity(name, args, 1).and_then(|_| readfile(&args[0])),
+        "writefile" => arity(name, args, 2).and_then(|_| writefile(&args[0], &args[1])),
+        "getenv" => arity(name, args, 1).map(|_| getenv(&args[0])),
+        "now" => arity(name, args, 0).map(|_| now()),
+        "random" => arity(name, args, 0).map(|_| Value::Float(random_float())),
+        "randint" => arity(name, args, 2).and_then(|_| randint(&args[0], &args[1])),
+        "throw" => {
+            return Some(arity(name, args, 1).map_err(BuiltinError::Failed).and_then(|_| Err(BuiltinError::Thrown(args[0].clone()))));
+        },
+        _ => return None
+    };
+    Some(result.map_err(BuiltinError::Failed))
 }
 
-impl<T: AstTrait> EvalResult<T> {
-    pub fn unwrap_push(self) -> T {
-        match self {
-            EvalResult::Push(res) => res,
-            _ => panic!()
-        }
-    }
-
-    pub fn unwrap_insert(self) -> T {
-        match self {
-            EvalResult::Insert(res) => res,
-            _ => panic!()
-        }
-    }
-
-    pub fn is_insert(&self) -> bool {
-        match *self {
-            EvalResult::Insert(_) => true,
-            _ => false
-        }
-    }
-
-    pub fn is_push(&self) -> bool {
-        match *self {
-            EvalResult::Push(_) => true,
-            _ => false
-        }
-    }
-
-    pub fn is_error(&self) -> bool {
-        match *self {
-            EvalResult::Error => true,
-            _ => false
-        }
-    }
-
-    pub fn is_ignore(&self) -> bool {
-        match *self {
-            EvalResult::Ignore => true,
-            _ => false
-        }
+fn arity(name: &str, args: &[Value], expected: usize) -> Result<(), String> {
+    match args.len() == expected {
+        true => Ok(()),
+        false => Err(format!("`{}` expects {} argument(s), found {}", name, expected, args.len()))
     }
 }
 
-pub enum SetEval<T: AstTrait> {
-    Skip(T),
-    Remove(T),
-    Other(T),
-    Error
-}
-
-impl<T: AstTrait> SetEval<T> {
-    pub fn is_skip(&self) -> bool {
-        match *self {
-            SetEval::Skip(_) => true,
-            _ => false
-        }
-    }
-
-    pub fn is_remove(&self) -> bool {
-        match *self {
-            SetEval::Remove(_) => true,
-            _ => false
-        }
-    }
-
-    pub fn is_other(&self) -> bool {
-        match *self {
-            SetEval::Other(_) => true,
-            _ => false
-        }
-    }
-
-    pub fn is_error(&self) -> bool {
-        match *self {
-            SetEval::Error => true,
-            _ => false
-        }
-    }
-
-    pub fn unwrap_skip(self) -> T {
-        match self {
-            SetEval::Skip(t) => t,
-            _ => panic!()
-        }
-    }
-
-    pub fn unwrap_remove(self) -> T {
-        match self {
-            SetEval::Remove(t) => t,
-            _ => panic!()
-        }
-    }
-
-    pub fn unwrap_other(self) -> T {
-        match self {
-            SetEval::Other(t) => t,
-            _ => panic!()
-        }
+fn print(args: &[Value]) -> Result<Value, String> {
+    for arg in args.iter() {
+        print!("{}", arg);
     }
+    Ok(Value::Nil)
 }
 
-fn print(mut ast: Ast) -> EvalResult<Ast> {
-    for child in ast.dump_children().into_iter() {
-        print!("{}", child.node_val.unwrap().get_lexed());
+fn println(args: &[Value]) -> Result<Value, String> {
+    for arg in args.iter() {
+        println!("{}", arg);
     }
-    EvalResult::Ignore
+    Ok(Value::Nil)
 }
 
-fn println(mut ast: Ast) -> EvalResult<Ast> {
-    for child in ast.dump_children().into_iter() {
-        println!("{}", child.node_val.unwrap().get_lexed());
+// A line of stdin without its line ending, or nil at the end of input.
+fn readline() -> Result<Value, String> {
+    let mut line = String::new();
+    match io::stdin().lock().read_line(&mut line) {
+        Ok(0) => Ok(Value::Nil),
+        Ok(_) => Ok(Value::Str(line.trim_end_matches(&['\n', '\r'][..]).to_string())),
+        Err(err) => Err(err.to_string())
     }
-    EvalResult::Ignore
 }
 
-fn add(mut ast: Ast) -> EvalResult<Ast> {
-    if ast.child_count() > 0 {
-        let mut int: i64 = 0;
-        let mut double: f64 = 0.0;
-        for child in ast.dump_children().into_iter() {
-            let child_val = child.node_val.unwrap().get_lexed();
-            match child_val.parse::<i64>() {
-                Ok(val) => int += val,
-                _ => {
-                    match child_val.parse::<f64>() {
-                        Ok(val) => double += val,
-                        _ => return EvalResult::Error
-                    }
-                }
-            }
-        }
-        ast.node_val = Some(match double == 0.0 {
-            true => Token::new_preset(int.to_string(), Type::Number),
-            false => Token::new_preset((double + int as f64).to_string(), Type::Number)
-        });
-        EvalResult::Push(ast)
-
-    } else {
-        EvalResult::Error
-    }
+fn readfile(path: &Value) -> Result<Value, String> {
+    fs::read_to_string(path.to_string()).map(Value::Str).map_err(|err| format!("could not read `{}`: {}", path, err))
 }
 
-fn sub(mut ast: Ast) -> EvalResult<Ast> {
-    if ast.child_count() > 0 {
-        let mut int: i64 = 0;
-        let mut double: f64 = 0.0;
-
-        let mut children = ast.dump_children();
-        let first = children.remove(0).node_val.unwrap().get_lexed();
-        match first.parse::<i64>() {
-            Ok(val) => int = val,
-            _ => {
-                match first.parse::<f64>() {
-                    Ok(val) => double = val,
-                    _ => return EvalResult::Error
-                }
-            }
-        }
-
-        for child in children.into_iter() {
-            let child_val = child.node_val.unwrap().get_lexed();
-            match child_val.parse::<i64>() {
-                Ok(val) => int -= val,
-                _ => {
-                    match child_val.parse::<f64>() {
-                        Ok(val) => double -= val,
-                        _ => return EvalResult::Error
-                    }
-                }
-            }
-        }
-        ast.node_val = Some(match double == 0.0 {
-            true => Token::new_preset(int.to_string(), Type::Number),
-            false => Token::new_preset((double + int as f64).to_string(), Type::Number)
-        });
-        EvalResult::Push(ast)
-    } else {
-        EvalResult::Error
+fn writefile(path: &Value, contents: &Value) -> Result<Value, String> {
+    match fs::write(path.to_string(), contents.to_string()) {
+        Ok(()) => Ok(Value::Nil),
+        Err(err) => Err(format!("could not write `{}`: {}", path, err))
     }
 }
 
-fn mult(mut ast: Ast) -> EvalResult<Ast> {
-    if ast.child_count() > 0 {
-        let mut int: i64 = 1;
-        let mut double: f64 = 1.0;
+fn getenv(name: &Value) -> Value {
+    env::var(name.to_string()).map(Value::Str).unwrap_or(Value::Nil)
+}
 
-        for child in ast.dump_children().into_iter() {
-            let child_val = child.node_val.unwrap().get_lexed();
-            match child_val.parse::<i64>() {
-                Ok(val) => int *= val,
-                _ => {
-                    match child_val.parse::<f64>() {
-                        Ok(val) => double *= val,
-                        _ => return EvalResult::Error
-                    }
-                }
-            }
-        }
-        ast.node_val = Some(match double == 1.0 {
-            true => Token::new_preset(int.to_string(), Type::Number),
-            false => Token::new_preset((double * int as f64).to_string(), Type::Number)
-        });
-        EvalResult::Push(ast)
-    } else {
-        EvalResult::Error
-    }
+// Milliseconds since the Unix epoch.
+fn now() -> Value {
+    let since = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    Value::Int(since.as_millis() as i64)
 }
 
-fn div(mut ast: Ast) -> EvalResult<Ast> {
-    if ast.child_count() > 0 {
-        let mut int: i64 = 1;
-        let mut double: f64 = 1.0;
-        let mut is_int = true;
+thread_local! {
+    static RANDOM_STATE: Cell<u64> = const { Cell::new(0) };
+}
 
-        let mut children = ast.dump_children();
-        let first = children.remove(0).node_val.unwrap().get_lexed();
-        match first.parse::<i64>() {
-            Ok(val) => int = val,
-            _ => {
-                match first.parse::<f64>() {
-                    Ok(val) => {
-                        double = val;
-                        is_int = !is_int;
-                    },
-                    _ => return EvalResult::Error
-                }
-            }
+// xorshift64*, seeded from the clock on first use. Not for anything that
+// needs to be unpredictable.
+fn random_u64() -> u64 {
+    RANDOM_STATE.with(|state| {
+        let mut x = state.get();
+        if x == 0 {
+            let since = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+            x = since.as_nanos() as u64 | 1;
         }
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        state.set(x);
+        x.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    })
+}
 
-        for child in children.into_iter() {
-            let child_val = child.node_val.unwrap().get_lexed();
-            match child_val.parse::<i64>() {
-                Ok(val) => {
-                    if is_int {
-                        int /= val;
-                    } else {
-                        double /= val as f64;
-                    }
-                },
-                _ => {
-                    match child_val.parse::<f64>() {
-                        Ok(val) => {
-                            if is_int {
-                                is_int = !is_int;
-                                double = int as f64;
-                            }
-                            double /= val;
-                        },
-                        _ => return EvalResult::Error
-                    }
-                }
-            }
-        }
-        ast.node_val = Some(match is_int {
-            true => Token::new_preset(int.to_string(), Type::Number),
-            false => Token::new_preset(double.to_string(), Type::Number)
-        });
-        EvalResult::Push(ast)
-    } else {
-        EvalResult::Error
-    }
+fn random_float() -> f64 {
+    (random_u64() >> 11) as f64 / (1u64 << 53) as f64
 }
 
-fn if_func(mut ast: Ast) -> EvalResult<Ast> {
-    let mut children = ast.dump_children();
-    let true_false = children.remove(0).get_child(0).unwrap().node_val.unwrap();
-    if true_false.get_lexed() == "true" {
-        EvalResult::Insert(children.remove(0))
-    } else if true_false.get_lexed() == "false" {
-        EvalResult::Insert(children.remove(1))
-    } else {
-        EvalResult::Error
+// An integer from low up to but not including high.
+fn randint(low: &Value, high: &Value) -> Result<Value, String> {
+    match (to_number(low)?, to_number(high)?) {
+        (Number::Int(low), Number::Int(high)) if low < high => {
+            let span = high.wrapping_sub(low) as u64;
+            Ok(Value::Int(low.wrapping_add((random_u64() % span) as i64)))
+        },
+        (Number::Int(_), Number::Int(_)) => Err("`randint` expects its first argument to be less than its second".to_string()),
+        _ => Err("`randint` expects two integers".to_string())
     }
 }
 
-fn eq(mut ast: Ast) -> EvalResult<Ast> {
-    let mut children = ast.dump_children();
-    if children.remove(0) == children.remove(0) {
-        EvalResult::Insert(generate_true())
-    } else {
-        EvalResult::Insert(generate_false())
-    }
+#[derive(Copy, Clone)]
+enum Number {
+    Int(i64),
+    Float(f64)
 }
 
-fn set(mut ast: Ast, scope: &mut Scope) -> EvalResult<Ast> {
-    let mut children = ast.dump_children();
-    let name = children.remove(0).get_child(0).unwrap().node_val.unwrap().get_lexed();
-    if {
-        match scope.find_func(&name) {
-            Some(func_option) => {
-                func_option.reset(children.remove(0).get_child(0).unwrap(), children.remove(0).get_child(0).unwrap());
-                false
-            },
-            None => true
+impl Number {
+    fn as_float(self) -> f64 {
+        match self {
+            Number::Int(int) => int as f64,
+            Number::Float(float) => float
         }
-    } {
-        scope.insert_func_no_search(Func::new(name, children.remove(0), children.remove(0)));
     }
-    EvalResult::Ignore
-}
 
-fn let_eval(mut ast: Ast, scope: &mut Scope) -> EvalResult<Ast> {
-    let mut children = ast.dump_children();
-    let name = children.remove(0).get_child(0).unwrap().node_val.unwrap().get_lexed();
-    if {
-        match scope.find_func(&name) {
-            Some(func_option) => {
-                func_option.reset(Ast::new_null(), children.remove(0).get_child(0).unwrap());
-                false
-            },
-            None => true
+    fn into_value(self) -> Value {
+        match self {
+            Number::Int(int) => Value::Int(int),
+            Number::Float(float) => Value::Float(float)
         }
-    } {
-        scope.insert_func_no_search(Func::new(name, Ast::new_null(), children.remove(0)));
     }
-    EvalResult::Ignore
 }
 
-pub fn evaluate_set_funcs(mut ast: Ast) -> SetEval<Ast> {
-    match ast.get_child(0) {
-        Some(mut child) => {
-            let mut node = child.node_val.unwrap();
-            match node.get_lexed().borrow() {
-                "set" => {
-                    node.set_lexed("seteval".to_string());
-                    child.node_val = Some(node);
-                    ast.insert_child(child, 0);
-                    SetEval::Skip(ast)
-                },
-                "let" => {
-                    node.set_lexed("leteval".to_string());
-                    child.node_val = Some(node);
-                    ast.insert_child(child, 0);
-                    SetEval::Skip(ast)
-                }
-                "name" => SetEval::Remove(ast),
-                "params" => SetEval::Remove(ast),
-                "body" => SetEval::Remove(ast.get_child(0).unwrap()),
-                _ => {
-                    child.node_val = Some(node);
-                    ast.insert_child(child, 0);
-                    SetEval::Other(ast)
-                }
+fn to_number(val: &Value) -> Result<Number, String> {
+    match *val {
+        Value::Int(int) => Ok(Number::Int(int)),
+        Value::Float(float) => Ok(Number::Float(float)),
+        Value::Str(ref string) => {
+            match token::parse_int(string) {
+                Some(int) => Ok(Number::Int(int)),
+                None => token::parse_float(string).map(Number::Float).ok_or_else(|| not_a_number(val))
             }
         },
-        None => SetEval::Error
+        _ => Err(not_a_number(val))
     }
 }
 
-pub fn evaluate_set(mut ast: Ast, scope: &mut Scope) -> EvalResult<Ast> {
-    match ast.get_child(0) {
-        Some(mut child) => {
-            let node = child.node_val.unwrap();
-            match node.get_lexed().borrow() {
-                "seteval" => set(ast, scope),
-                "leteval" => let_eval(ast, scope),
-                _ => {
-                    child.node_val = Some(node);
-                    ast.insert_child(child, 0);
-                    EvalResult::Insert(ast)
+// Folds the arguments left to right, staying in integers until a float
+// shows up.
+fn arithmetic(name: &str, args: &[Value], int_op: fn(i64, i64) -> Option<i64>,
+              float_op: fn(f64, f64) -> f64) -> Result<Value, String> {
+    if args.is_empty() {
+        return Err(format!("`{}` expects at least one argument", name));
+    }
+    let mut acc = to_number(&args[0])?;
+    for arg in args[1..].iter() {
+        acc = match (acc, to_number(arg)?) {
+            (Number::Int(a), Number::Int(b)) => {
+                match int_op(a, b) {
+                    Some(int) => Number::Int(int),
+                    None => return Err(format!("integer overflow in `{}`", name))
                 }
-            }
-        },
-        None => EvalResult::Error
+            },
+            (a, b) => Number::Float(float_op(a.as_float(), b.as_float()))
+        };
     }
+    Ok(acc.into_value())
 }
 
-pub fn evaluate_builtin(mut ast: Ast) -> EvalResult<Ast> {
-    match ast.get_child(0) {
-        Some(mut child) => {
-            let node = child.node_val.unwrap();
-            child.node_val = Some(node);
-            match child.node_val.unwrap().get_lexed().borrow() {
-                "print" => print(ast),
-                "println" => println(ast),
-                "add" => add(ast),
-                "sub" => sub(ast),
-                "mult" => mult(ast),
-                "div" => div(ast),
-                "if" => if_func(ast),
-                "eq" => eq(ast),
-                _ => EvalResult::Ignore
-            }
-        },
-        None => EvalResult::Error
+fn div(args: &[Value]) -> Result<Value, String> {
+    for arg in args.iter().skip(1) {
+        if let Ok(Number::Int(0)) = to_number(arg) {
+            return Err("division by zero".to_string());
+        }
     }
+    arithmetic("div", args, i64::checked_div, |a, b| a / b)
 }
 
-pub fn generate_let_ast(tok: Token, ast: Ast) -> Ast {
-    let mut func_ast = Ast::new(Token::new_preset('('.to_string(), Type::Oparen));
-    func_ast.push_child(Ast::new(Token::new_preset("let".to_string(), Type::Func)));
-    func_ast.push_child({
-        let mut func_name = Ast::new(Token::new_preset('('.to_string(), Type::Oparen));
-        func_name.push_child(Ast::new(Token::new_preset("name".to_string(), Type::Func)));
-        func_name.push_child(Ast::new(tok));
-        func_name
-    });
-    func_ast.push_child({
-        let mut func_body = Ast::new(Token::new_preset('('.to_string(), Type::Oparen));
-        func_body.push_child(Ast::new(Token::new_preset("body".to_string(), Type::Func)));
-        func_body.push_child(ast);
-        func_body
-    });
-    func_ast
-}
-
-pub fn generate_true() -> Ast {
-    Ast::new(Token::new_preset("true".to_string(), Type::StrType))
+fn eq(args: &[Value]) -> Result<Value, String> {
+    if args.len() < 2 {
+        return Err("`eq` expects at least two arguments".to_string());
+    }
+    let first = &args[0];
+    Ok(Value::from_bool(args[1..].iter().all(|arg| {
+        match (to_number(first), to_number(arg)) {
+            (Ok(a), Ok(b)) if first.is_number() && arg.is_number() => a.as_float() == b.as_float(),
+            _ => arg == first
+        }
+    })))
 }
 
-pub fn generate_false() -> Ast {
-    Ast::new(Token::new_preset("false".to_string(), Type::StrType))
+fn not_a_number(val: &Value) -> String {
+    format!("expected a number, found `{}`", val)
 }
@@ -0,0 +1,317 @@
+use ast::Ast;
+use eval::{Func, RuntimeError, RuntimeErrorKind};
+use expr::{self, Expr};
+use intern::Symbol;
+use std::collections::HashMap;
+use std::rc::Rc;
+use token::Span;
+use value::Value;
+
+// One instruction. Constants and spans are indexes into the proto's own
+// tables, and jump targets are offsets into its code.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Op {
+    Const(u32),
+    Symbol(Symbol),
+    Nil,
+    Pop,
+    MakeList(u32),
+    // Calls through one of the running function's own parameters.
+    CallLocal { slot: u32, argc: u32, at: u32 },
+    // Calls a name looked up when the call happens, as Eval does.
+    CallName { name: Symbol, argc: u32, at: u32 },
+    // Calls the value below the arguments.
+    CallValue { argc: u32, at: u32 },
+    // Jumps to the offset when the condition is false. The form and the
+    // condition's span are for the error when it is neither.
+    Branch { to: u32, form: Symbol, at: u32 },
+    Jump(u32),
+    SetLocal { slot: u32, proto: u32 },
+    SetName { name: Symbol, proto: u32 },
+    BindLocal(u32),
+    BindName(Symbol),
+    // Calls a proto directly, as a catch handler is.
+    CallProto { proto: u32, argc: u32, at: u32 },
+    // Catches errors until the matching EndTry by jumping to the offset,
+    // with the error's value pushed and the error kept for Rethrow.
+    Try(u32),
+    EndTry,
+    // Drops the kept error once it is handled.
+    Forget,
+    Rethrow,
+    Return
+}
+
+// A compiled function body. The program itself is a proto without params.
+#[derive(Clone, Debug)]
+pub struct Proto {
+    pub name: Symbol,
+    pub params: Vec<Symbol>,
+    pub code: Vec<Op>,
+    pub consts: Vec<Value>,
+    pub spans: Vec<Span>
+}
+
+impl Proto {
+    fn new(name: Symbol, params: Vec<Symbol>) -> Self {
+        Proto {
+            name: name,
+            params: params,
+            code: Vec::new(),
+            consts: Vec::new(),
+            spans: Vec::new()
+        }
+    }
+}
+
+// Everything compiled so far, so lambdas made by one program can still be
+// called from the next.
+pub struct Program {
+    pub protos: Vec<Proto>,
+    // Lambda values by the address of their Func. The Rc is kept with the
+    // proto so the address can not be reused by another Func while the
+    // entry exists.
+    lambdas: HashMap<usize, (Rc<Func>, u32)>
+}
+
+impl Program {
+    pub fn new() -> Self {
+        Program {
+            protos: Vec::new(),
+            lambdas: HashMap::new()
+        }
+    }
+
+    // Compiles a parsed program, returning the index of its proto.
+    pub fn compile(&mut self, ast: &Ast) -> Result<u32, RuntimeError> {
+        match expr::lower(ast) {
+            Ok(program) => Ok(self.compile_exprs(&program)),
+            Err(err) => {
+                let span = match err.span.is_dummy() {
+                    true => None,
+                    false => Some(err.span)
+                };
+                Err(RuntimeError::new(RuntimeErrorKind::MalformedForm, err.message, span))
+            }
+        }
+    }
+
+    // Every form but the last has its value dropped, the last one's is the
+    // program's value.
+    pub fn compile_exprs(&mut self, program: &[Expr]) -> u32 {
+        let mut emitter = Emitter::new(Proto::new(Symbol::intern("main"), Vec::new()));
+        for (i, expr) in program.iter().enumerate() {
+            if i > 0 {
+                emitter.emit(Op::Pop);
+            }
+            emitter.expr(self, expr);
+        }
+        if program.is_empty() {
+            emitter.emit(Op::Nil);
+        }
+        self.finish(emitter)
+    }
+
+    pub fn compile_func(&mut self, name: Symbol, params: &[Symbol], body: &Expr) -> u32 {
+        let mut emitter = Emitter::new(Proto::new(name, params.to_vec()));
+        emitter.expr(self, body);
+        self.finish(emitter)
+    }
+
+    // The proto for a lambda value, compiling it if the value was not made
+    // by this program.
+    pub fn lambda_proto(&mut self, func: &Rc<Func>) -> u32 {
+        let key = Rc::as_ptr(func) as usize;
+        match self.lambdas.get(&key) {
+            Some(&(_, proto)) => proto,
+            None => {
+                let proto = self.compile_func(func.get_name(), &func.params, &func.body);
+                self.lambdas.insert(key, (func.clone(), proto));
+                proto
+            }
+        }
+    }
+
+    fn finish(&mut self, mut emitter: Emitter) -> u32 {
+        emitter.emit(Op::Return);
+        self.protos.push(emitter.proto);
+        (self.protos.len() - 1) as u32
+    }
+}
+
+struct Emitter {
+    proto: Proto
+}
+
+impl Emitter {
+    fn new(proto: Proto) -> Self {
+        Emitter {
+            proto: proto
+        }
+    }
+
+    fn emit(&mut self, op: Op) -> usize {
+        self.proto.code.push(op);
+        self.proto.code.len() - 1
+    }
+
+    fn constant(&mut self, val: Value) -> u32 {
+        self.proto.consts.push(val);
+        (self.proto.consts.len() - 1) as u32
+    }
+
+    fn span(&mut self, span: Span) -> u32 {
+        self.proto.spans.push(span);
+        (self.proto.spans.len() - 1) as u32
+    }
+
+    // Runs a try's finally form, if it has one, for its effects alone.
+    fn finally(&mut self, program: &mut Program, finally: &Option<Box<Expr>>) {
+        if let Some(ref finally) = *finally {
+            self.expr(program, finally);
+            self.emit(Op::Pop);
+        }
+    }
+
+    // The slot of one of the function's own params.
+    fn local(&self, name: Symbol) -> Option<u32> {
+        self.proto.params.iter().position(|param| *param == name).map(|slot| slot as u32)
+    }
+
+    fn expr(&mut self, program: &mut Program, expr: &Expr) {
+        match *expr {
+            Expr::Literal(ref val, _) => {
+                let index = self.constant(val.clone());
+                self.emit(Op::Const(index));
+            },
+            Expr::Symbol(name, _) => {
+                self.emit(Op::Symbol(name));
+            },
+            Expr::List(ref items, _) => {
+                for item in items.iter() {
+                    self.expr(program, item);
+                }
+                self.emit(Op::MakeList(items.len() as u32));
+            },
+            Expr::Set { name, ref params, ref body, .. } => {
+                let proto = program.compile_func(name, params, body);
+                match self.local(name) {
+                    Some(slot) => self.emit(Op::SetLocal { slot: slot, proto: proto }),
+                    None => self.emit(Op::SetName { name: name, proto: proto })
+                };
+            },
+            Expr::Let { name, ref body, .. } => {
+                self.expr(program, body);
+                match self.local(name) {
+                    Some(slot) => self.emit(Op::BindLocal(slot)),
+                    None => self.emit(Op::BindName(name))
+                };
+            },
+            Expr::Lambda { ref params, ref body, .. } => {
+                let lambda = Symbol::intern("lambda");
+                let func = Rc::new(Func::new(lambda, params.clone(), (**body).clone()));
+                let proto = program.compile_func(lambda, params, body);
+                program.lambdas.insert(Rc::as_ptr(&func) as usize, (func.clone(), proto));
+                let index = self.constant(Value::Lambda(func));
+                self.emit(Op::Const(index));
+            },
+            Expr::If { ref cond, ref then, ref otherwise, form, .. } => {
+                self.expr(program, cond);
+                let at = self.span(cond.span());
+                let branch = self.emit(Op::Branch { to: 0, form: form, at: at });
+                self.expr(program, then);
+                let jump = self.emit(Op::Jump(0));
+                self.proto.code[branch] = Op::Branch { to: self.proto.code.len() as u32, form: form, at: at };
+                self.expr(program, otherwise);
+                self.proto.code[jump] = Op::Jump(self.proto.code.len() as u32);
+            },
+            Expr::Try { ref body, ref catch, ref finally, span } => {
+                let enter = self.emit(Op::Try(0));
+                self.expr(program, body);
+                self.emit(Op::EndTry);
+                self.finally(program, finally);
+                let mut done = vec![self.emit(Op::Jump(0))];
+                self.proto.code[enter] = Op::Try(self.proto.code.len() as u32);
+                match *catch {
+                    Some((param, ref handler)) => {
+                        let proto = program.compile_func(Symbol::intern("catch"), &[param], handler);
+                        let at = self.span(span);
+                        self.emit(Op::Forget);
+                        // An error in the handler still runs the finally.
+                        let guard = finally.as_ref().map(|_| self.emit(Op::Try(0)));
+                        self.emit(Op::CallProto { proto: proto, argc: 1, at: at });
+                        if let Some(guard) = guard {
+                            self.emit(Op::EndTry);
+                            self.finally(program, finally);
+                            done.push(self.emit(Op::Jump(0)));
+                            self.proto.code[guard] = Op::Try(self.proto.code.len() as u32);
+                            self.finally(program, finally);
+                            self.emit(Op::Rethrow);
+                        }
+                    },
+                    None => {
+                        self.finally(program, finally);
+                        self.emit(Op::Rethrow);
+                    }
+                }
+                for jump in done {
+                    self.proto.code[jump] = Op::Jump(self.proto.code.len() as u32);
+                }
+            },
+            Expr::Do(ref forms, _) => {
+                if forms.is_empty() {
+                    let index = self.constant(Value::Nil);
+                    self.emit(Op::Const(index));
+                }
+                for (i, form) in forms.iter().enumerate() {
+                    if i > 0 {
+                        self.emit(Op::Pop);
+                    }
+                    self.expr(program, form);
+                }
+            },
+            Expr::Call { ref head, ref args, span } => {
+                let argc = args.len() as u32;
+                let local = match **head {
+                    Expr::Symbol(name, _) => Some(self.local(name).ok_or(name)),
+                    _ => None
+                };
+                if local.is_none() {
+                    self.expr(program, head);
+                }
+                for arg in args.iter() {
+                    self.expr(program, arg);
+                }
+                let at = self.span(span);
+                match local {
+                    Some(Ok(slot)) => self.emit(Op::CallLocal { slot: slot, argc: argc, at: at }),
+                    Some(Err(name)) => self.emit(Op::CallName { name: name, argc: argc, at: at }),
+                    None => self.emit(Op::CallValue { argc: argc, at: at })
+                };
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lambda(params: &[&str]) -> Rc<Func> {
+        let params = params.iter().map(|param| Symbol::intern(param)).collect();
+        Rc::new(Func::new(Symbol::intern("lambda"), params, Expr::Literal(Value::Nil, Span::default())))
+    }
+
+    #[test]
+    fn lambda_protos_follow_the_func_not_its_address() {
+        let mut program = Program::new();
+        for round in 0..100 {
+            let params: Vec<String> = (0..round % 4).map(|i| format!("p{}", i)).collect();
+            let params: Vec<&str> = params.iter().map(String::as_str).collect();
+            let func = lambda(&params);
+            let proto = program.lambda_proto(&func);
+            assert_eq!(program.protos[proto as usize].params, func.params);
+            assert_eq!(program.lambda_proto(&func), proto);
+        }
+    }
+}
@@ -0,0 +1,104 @@
+use builtins::{self, BuiltinError};
+use limits::Limits;
+use std::rc::Rc;
+use value::Value;
+
+// A group of builtins that reach outside the interpreter. Scripts can only
+// call a group's builtins when their config allows it.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Capability {
+    ConsoleIo,
+    FileIo,
+    Env,
+    Time,
+    Random,
+    HostFunctions
+}
+
+pub const ALL_CAPABILITIES: &[Capability] = &[Capability::ConsoleIo, Capability::FileIo, Capability::Env,
+                                              Capability::Time, Capability::Random, Capability::HostFunctions];
+
+pub type HostFn = Rc<dyn Fn(&[Value]) -> Result<Value, String>>;
+
+// What scripts may do and how far they may go. The default is the pure
+// profile: arithmetic, comparison and the special forms only, no limits.
+// A builtin the config denies is an unknown function to the script.
+#[derive(Clone, Default)]
+pub struct InterpreterConfig {
+    capabilities: Vec<Capability>,
+    pub limits: Limits,
+    host_functions: Vec<(String, HostFn)>
+}
+
+impl InterpreterConfig {
+    pub fn new() -> Self {
+        InterpreterConfig::default()
+    }
+
+    // Every capability, for scripts we wrote ourselves.
+    pub fn trusted() -> Self {
+        let mut config = InterpreterConfig::new();
+        config.capabilities = ALL_CAPABILITIES.to_vec();
+        config
+    }
+
+    pub fn allow(mut self, capability: Capability) -> Self {
+        if !self.allows(capability) {
+            self.capabilities.push(capability);
+        }
+        self
+    }
+
+    pub fn deny(mut self, capability: Capability) -> Self {
+        self.capabilities.retain(|allowed| *allowed != capability);
+        self
+    }
+
+    pub fn limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    // Makes func callable by name from scripts, once HostFunctions is
+    // allowed. It replaces a host function of the same name, but builtins
+    // and the script's own definitions come first.
+    pub fn host_function<F>(mut self, name: &str, func: F) -> Self
+        where F: Fn(&[Value]) -> Result<Value, String> + 'static
+    {
+        self.host_functions.retain(|host| host.0 != name);
+        self.host_functions.push((name.to_string(), Rc::new(func)));
+        self
+    }
+
+    pub fn allows(&self, capability: Capability) -> bool {
+        self.capabilities.contains(&capability)
+    }
+
+    fn host(&self, name: &str) -> Option<&HostFn> {
+        match self.allows(Capability::HostFunctions) {
+            true => self.host_functions.iter().find(|host| host.0 == name).map(|host| &host.1),
+            false => None
+        }
+    }
+
+    // Whether scripts under this config can call the builtin or host
+    // function called name.
+    pub fn has_builtin(&self, name: &str) -> bool {
+        match builtins::BUILTINS.contains(&name) {
+            true => builtins::capability(name).is_none_or(|capability| self.allows(capability)),
+            false => self.host(name).is_some()
+        }
+    }
+
+    // Runs the builtin or host function called name, or returns None if the
+    // config has no such function.
+    pub fn call_builtin(&self, name: &str, args: &[Value]) -> Option<Result<Value, BuiltinError>> {
+        if builtins::BUILTINS.contains(&name) {
+            return match self.has_builtin(name) {
+                true => builtins::call_builtin(name, args),
+                false => None
+            };
+        }
+        self.host(name).map(|host| host(args).map_err(BuiltinError::Failed))
+    }
+}
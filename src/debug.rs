@@ -0,0 +1,204 @@
+use eval::{Call, Eval, EvalTrait, RuntimeError, Scope};
+use intern::Symbol;
+use token::Span;
+use value::Value;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Breakpoint {
+    // Stops when evaluation arrives at a form on the line, but not again
+    // for the other forms on it.
+    Line(usize),
+    // Stops on entering any call to the function.
+    Function(Symbol)
+}
+
+// Breakpoints keep their id when others are removed.
+#[derive(Clone, Debug, Default)]
+pub struct Breakpoints {
+    list: Vec<Option<Breakpoint>>
+}
+
+impl Breakpoints {
+    pub fn new() -> Self {
+        Breakpoints::default()
+    }
+
+    pub fn add(&mut self, breakpoint: Breakpoint) -> usize {
+        self.list.push(Some(breakpoint));
+        self.list.len() - 1
+    }
+
+    pub fn remove(&mut self, id: usize) -> Option<Breakpoint> {
+        self.list.get_mut(id).and_then(|breakpoint| breakpoint.take())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &Breakpoint)> {
+        self.list.iter().enumerate().filter_map(|(id, breakpoint)| breakpoint.as_ref().map(|breakpoint| (id, breakpoint)))
+    }
+
+    fn at_line(&self, line: usize) -> Option<usize> {
+        self.iter().find(|&(_, breakpoint)| *breakpoint == Breakpoint::Line(line)).map(|found| found.0)
+    }
+
+    fn at_function(&self, name: Symbol) -> Option<usize> {
+        self.iter().find(|&(_, breakpoint)| *breakpoint == Breakpoint::Function(name)).map(|found| found.0)
+    }
+}
+
+// Why the debugger handed control back.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Stop {
+    // A step finished, before the form at the span.
+    Step(Span),
+    // The breakpoint with the id was hit, before the form at the span.
+    Breakpoint(usize, Span),
+    Finished(Result<Value, RuntimeError>)
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum Mode {
+    Into,
+    Over,
+    Out,
+    Resume
+}
+
+// Runs an Eval a few tasks at a time, stopping before forms. Stops only
+// happen before forms other than literals and names, so a step always
+// lands on something worth looking at.
+pub struct Debugger {
+    eval: Eval,
+    scope: Option<Scope>,
+    pub breakpoints: Breakpoints,
+    // The line and depth of the last form passed, so a line breakpoint
+    // stops when evaluation arrives at the line rather than at every form
+    // on it.
+    last: Option<(usize, usize)>,
+    at: Option<Span>,
+    result: Option<Result<Value, RuntimeError>>
+}
+
+impl Debugger {
+    pub fn new(eval: Eval, scope: Scope) -> Self {
+        Debugger {
+            eval: eval,
+            scope: Some(scope),
+            breakpoints: Breakpoints::new(),
+            last: None,
+            at: None,
+            result: None
+        }
+    }
+
+    // Stops before the next form, wherever it is.
+    pub fn step_into(&mut self) -> Stop {
+        self.run(Mode::Into)
+    }
+
+    // Stops before the next form in the same call or one of its callers,
+    // running any calls made in between.
+    pub fn step_over(&mut self) -> Stop {
+        self.run(Mode::Over)
+    }
+
+    // Stops before the next form once the current call has returned.
+    pub fn step_out(&mut self) -> Stop {
+        self.run(Mode::Out)
+    }
+
+    // Runs until a breakpoint or the end.
+    pub fn resume(&mut self) -> Stop {
+        self.run(Mode::Resume)
+    }
+
+    // Where evaluation is stopped, None before the first stop and after the
+    // end.
+    pub fn location(&self) -> Option<Span> {
+        match self.result {
+            Some(_) => None,
+            None => self.at
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.result.is_some()
+    }
+
+    // The user function calls in progress, innermost last.
+    pub fn backtrace(&self) -> &[Call] {
+        self.eval.calls()
+    }
+
+    // The innermost scope. Its parents are the scopes of the calls below it
+    // and then the scope the program started with.
+    pub fn scope(&self) -> &Scope {
+        self.scope.as_ref().unwrap()
+    }
+
+    pub fn pending(&self) -> Vec<String> {
+        self.eval.pending()
+    }
+
+    pub fn values(&self) -> &[Value] {
+        self.eval.values()
+    }
+
+    // Gives back the scope the program started with, with whatever it
+    // defined, even if the program was not finished.
+    pub fn into_scope(mut self) -> Scope {
+        let scope = self.scope.take().unwrap();
+        self.eval.finish(scope).0
+    }
+
+    fn run(&mut self, mode: Mode) -> Stop {
+        let depth = self.eval.depth();
+        let mut first = true;
+        loop {
+            if let Some(ref result) = self.result {
+                return Stop::Finished(result.clone());
+            }
+            if self.eval.is_evaluated() {
+                let (scope, result) = self.eval.finish(self.scope.take().unwrap());
+                self.scope = Some(scope);
+                self.result = Some(result);
+                continue;
+            }
+            let form = self.eval.next_form().map(|expr| expr.span());
+            if let Some(span) = form {
+                let at = (span.line, self.eval.depth());
+                let new_line = self.last != Some(at);
+                self.last = Some(at);
+                if !first {
+                    self.at = Some(span);
+                    if let Some(id) = self.breakpoints.at_line(span.line).filter(|_| new_line) {
+                        return Stop::Breakpoint(id, span);
+                    }
+                    let stop = match mode {
+                        Mode::Into => true,
+                        Mode::Over => at.1 <= depth,
+                        Mode::Out => at.1 < depth,
+                        Mode::Resume => false
+                    };
+                    if stop {
+                        return Stop::Step(span);
+                    }
+                }
+            }
+            first = false;
+
+            let calls = self.eval.calls().len();
+            let scope = self.eval.step(self.scope.take().unwrap());
+            self.scope = Some(scope);
+            if self.eval.calls().len() > calls {
+                let name = self.eval.calls()[calls].name;
+                if let Some(id) = self.breakpoints.at_function(name) {
+                    let span = self.eval.next_form().map(|expr| expr.span()).unwrap_or(self.eval.calls()[calls].span);
+                    self.at = Some(span);
+                    // Evaluating the body is not a second stop.
+                    self.last = Some((span.line, self.eval.depth()));
+                    return Stop::Breakpoint(id, span);
+                }
+            }
+        }
+    }
+}
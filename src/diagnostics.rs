@@ -0,0 +1,274 @@
+use eval::RuntimeError;
+use expr::LowerError;
+use lex::{LexError, LexErrorKind};
+use lint::{Lint, LintKind};
+use parse::{ParseError, ParseErrorKind};
+use token::Span;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Style {
+    Plain,
+    Ansi
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    pub span: Option<Span>,
+    pub help: Option<String>,
+    // Calls to list under the message, innermost first.
+    pub backtrace: Vec<Frame>
+}
+
+// A call's name and where it was made.
+pub type Frame = (String, Option<Span>);
+
+pub trait Render {
+    fn render(&self, file_name: &str, source: &str, style: Style) -> String;
+}
+
+impl Diagnostic {
+    pub fn error(message: String, span: Option<Span>) -> Self {
+        Diagnostic {
+            severity: Severity::Error,
+            message: message,
+            span: span,
+            help: None,
+            backtrace: Vec::new()
+        }
+    }
+
+    pub fn warning(message: String, span: Option<Span>) -> Self {
+        Diagnostic {
+            severity: Severity::Warning,
+            message: message,
+            span: span,
+            help: None,
+            backtrace: Vec::new()
+        }
+    }
+
+    pub fn with_help(mut self, help: String) -> Self {
+        self.help = Some(help);
+        self
+    }
+}
+
+impl<'a> From<&'a LexError> for Diagnostic {
+    fn from(err: &'a LexError) -> Self {
+        let diag = Diagnostic::error(err.to_string(), Some(err.span));
+        match err.kind {
+            LexErrorKind::InvalidCharacter(_) => diag,
+            LexErrorKind::InvalidNumber(_) => {
+                diag.with_help("numbers may have a sign, a `0x`, `0o` or `0b` prefix, `_` separators and an exponent".to_string())
+            },
+            LexErrorKind::UnterminatedString => diag.with_help("add a closing `\"`".to_string()),
+            LexErrorKind::Io(_) => diag
+        }
+    }
+}
+
+impl<'a> From<&'a ParseError> for Diagnostic {
+    fn from(err: &'a ParseError) -> Self {
+        let diag = Diagnostic::error(err.to_string(), Some(err.span));
+        match err.kind {
+            ParseErrorKind::UnexpectedClose(_) => diag.with_help("there is no open form for this to close".to_string()),
+            ParseErrorKind::MismatchedClose { ref expected, ref open, .. } => {
+                diag.with_help(format!("the form opened at {}:{} must be closed with `{}`", open.line, open.col, expected))
+            },
+            ParseErrorKind::Unclosed(_) => diag.with_help("this form is never closed".to_string()),
+            ParseErrorKind::LiteralOutsideForm(_) => diag.with_help("only forms may appear at the top level".to_string()),
+            ParseErrorKind::InvalidToken(_) => diag
+        }
+    }
+}
+
+impl<'a> From<&'a LowerError> for Diagnostic {
+    fn from(err: &'a LowerError) -> Self {
+        Diagnostic::error(err.to_string(), Some(err.span))
+    }
+}
+
+impl<'a> From<&'a RuntimeError> for Diagnostic {
+    fn from(err: &'a RuntimeError) -> Self {
+        let mut diag = Diagnostic::error(err.to_string(), err.span);
+        if !err.backtrace.is_empty() {
+            diag.backtrace = err.backtrace.iter().rev().map(|call| {
+                let span = match call.span.is_dummy() {
+                    true => None,
+                    false => Some(call.span)
+                };
+                (call.name.to_string(), span)
+            }).collect();
+            diag.backtrace.push(("<top level>".to_string(), None));
+        }
+        diag
+    }
+}
+
+impl<'a> From<&'a Lint> for Diagnostic {
+    fn from(lint: &'a Lint) -> Self {
+        let diag = match lint.is_error() {
+            true => Diagnostic::error(lint.to_string(), Some(lint.span)),
+            false => Diagnostic::warning(lint.to_string(), Some(lint.span))
+        };
+        match lint.kind {
+            LintKind::UnusedLet(ref name) => diag.with_help(format!("remove the binding or use it as `({})`", name)),
+            LintKind::ShadowedBuiltin(_) => diag.with_help("the builtin is no longer reachable after this".to_string()),
+            LintKind::MalformedSet(_) => diag.with_help("write it as `(set (name f) (params a b) (body ...))`".to_string()),
+            LintKind::MalformedLet(_) => diag.with_help("write it as `(let (name x) (body ...))`".to_string()),
+            _ => diag
+        }
+    }
+}
+
+impl Render for Diagnostic {
+    fn render(&self, file_name: &str, source: &str, style: Style) -> String {
+        let paint = |code: &str, text: &str| -> String {
+            match style {
+                Style::Plain => text.to_string(),
+                Style::Ansi => format!("\x1b[{}m{}\x1b[0m", code, text)
+            }
+        };
+        let (label, color) = match self.severity {
+            Severity::Error => ("error", "1;31"),
+            Severity::Warning => ("warning", "1;33")
+        };
+        let mut rendered = format!("{}{}\n", paint(color, label), paint("1", &format!(": {}", self.message)));
+        let mut backtrace = String::new();
+        if !self.backtrace.is_empty() {
+            backtrace.push_str(&format!("{}\n", paint("1", "stack backtrace:")));
+            let runs = frame_runs(&self.backtrace);
+            for (n, &(i, &(ref name, span), count)) in runs.iter().enumerate() {
+                if runs.len() > 2 * EDGE_FRAMES && n >= EDGE_FRAMES && n < runs.len() - EDGE_FRAMES {
+                    if n == EDGE_FRAMES {
+                        let omitted = runs[EDGE_FRAMES..runs.len() - EDGE_FRAMES].iter().map(|run| run.2).sum::<usize>();
+                        backtrace.push_str(&format!("      ... {} more frames\n", omitted));
+                    }
+                    continue;
+                }
+                let repeats = match count {
+                    1 => String::new(),
+                    _ => format!(" \u{d7}{}", count)
+                };
+                match span {
+                    Some(span) => {
+                        backtrace.push_str(&format!("{:>4}: {} at {}:{}:{}{}\n", i, name, file_name, span.line, span.col, repeats))
+                    },
+                    None => backtrace.push_str(&format!("{:>4}: {}{}\n", i, name, repeats))
+                }
+            }
+        }
+
+        // A span past the end of source is from some other source, as when
+        // the REPL calls a function loaded from a file.
+        let span = match self.span {
+            Some(span) if !span.is_dummy() && source.is_char_boundary(span.end) => span,
+            _ => {
+                rendered.push_str(&format!("{} {}\n", paint("1;34", "-->"), file_name));
+                if let Some(ref help) = self.help {
+                    rendered.push_str(&format!("{} {}\n", paint("1;34", "="), paint("1", &format!("help: {}", help))));
+                }
+                rendered.push_str(&backtrace);
+                return rendered;
+            }
+        };
+
+        let line_text = source.lines().nth(span.line - 1).unwrap_or("");
+        let line_no = span.line.to_string();
+        let gutter = " ".repeat(line_no.len());
+        let pipe = paint("1;34", "|");
+
+        // Keep tabs from the source line so the carets stay lined up.
+        let indent: String = line_text.chars().take(span.col - 1).map(|c| match c {
+            '\t' => '\t',
+            _ => ' '
+        }).collect();
+        let line_start = line_start_offset(source, span.start);
+        let line_end = line_start + line_text.len();
+        let underline_end = match span.end > line_end {
+            true => line_end,
+            false => span.end
+        };
+        let width = match underline_end > span.start {
+            true => source[span.start..underline_end].chars().count(),
+            false => 1
+        };
+
+        rendered.push_str(&format!("{}{} {}:{}:{}\n", gutter, paint("1;34", "-->"), file_name, span.line, span.col));
+        rendered.push_str(&format!("{} {}\n", gutter, pipe));
+        rendered.push_str(&format!("{} {} {}\n", paint("1;34", &line_no), pipe, line_text));
+        rendered.push_str(&format!("{} {} {}{}\n", gutter, pipe, indent, paint(color, &"^".repeat(width))));
+        if let Some(ref help) = self.help {
+            rendered.push_str(&format!("{} {}\n", gutter, pipe));
+            rendered.push_str(&format!("{} {} {}\n", gutter, paint("1;34", "="), paint("1", &format!("help: {}", help))));
+        }
+        rendered.push_str(&backtrace);
+        rendered
+    }
+}
+
+// Each run of the same call, as a runaway recursion makes, is shown once
+// with its count. Past twice this many runs only the first and last are.
+const EDGE_FRAMES: usize = 10;
+
+// The backtrace as runs of identical frames, each with the index of its
+// first frame and its length.
+fn frame_runs(backtrace: &[Frame]) -> Vec<(usize, &Frame, usize)> {
+    let mut runs: Vec<(usize, &Frame, usize)> = Vec::new();
+    for (i, frame) in backtrace.iter().enumerate() {
+        match runs.last_mut() {
+            Some(run) if run.1 == frame => run.2 += 1,
+            _ => runs.push((i, frame, 1))
+        }
+    }
+    runs
+}
+
+fn line_start_offset(source: &str, offset: usize) -> usize {
+    match source[..offset].rfind('\n') {
+        Some(newline) => newline + 1,
+        None => 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::InterpreterConfig;
+    use limits::Limits;
+    use {Error, Interpreter};
+
+    fn rendered(src: &str) -> String {
+        let config = InterpreterConfig::new().limits(Limits::new().max_depth(1000));
+        match Interpreter::with_config(config).eval_str(src) {
+            Err(Error::Runtime(ref err)) => Diagnostic::from(err).render("t.hk", src, Style::Plain),
+            other => panic!("expected a runtime error, found {:?}", other.map(|val| val.to_string()))
+        }
+    }
+
+    #[test]
+    fn runaway_recursion_shows_each_frame_once_with_its_count() {
+        let src = "(set (name down) (params n) (body (down (n))))\n(down 1)";
+        let rendered = rendered(src);
+        assert!(rendered.contains("   0: down at t.hk:1:35 \u{d7}999\n"), "{}", rendered);
+        assert!(rendered.contains(" 999: down at t.hk:2:1\n"), "{}", rendered);
+        assert!(rendered.ends_with("1000: <top level>\n"), "{}", rendered);
+    }
+
+    #[test]
+    fn long_backtraces_keep_only_their_ends() {
+        let src = "(set (name f) (params n) (body (g (n))))\n(set (name g) (params n) (body (f (n))))\n(f 1)";
+        let rendered = rendered(src);
+        assert!(rendered.contains("      ... 981 more frames\n"), "{}", rendered);
+        assert_eq!(rendered.lines().filter(|line| line.contains(" at t.hk:")).count(), 2 * EDGE_FRAMES - 1);
+        assert!(rendered.ends_with("1000: <top level>\n"), "{}", rendered);
+    }
+}
@@ -1,104 +1,201 @@
-use ast::{Ast, AstTrait};
-use token::{TokenTrait, Type};
-use builtins;
-use std::ops::IndexMut;
+use ast::{self, Ast};
+use builtins::BuiltinError;
+use config::InterpreterConfig;
+use expr::{self, Expr};
+use intern::{self, Symbol, SymbolMap};
+use limits::Limits;
+use profile::Profiler;
+use std::error::Error;
+use std::fmt;
+use std::mem;
+use std::rc::Rc;
+use std::sync::atomic::Ordering;
+use std::time::Instant;
+use token::Span;
+use trace::{SharedTracer, SpecialForm, Tracer};
+use value::Value;
 
 pub struct Eval {
     ast: Ast,
-    stack: Vec<(Ast, usize)>,
-    evaluated: bool
+    program: Vec<Expr>,
+    tasks: Vec<Task>,
+    values: Vec<Value>,
+    depth: usize,
+    evaluated: bool,
+    error: Option<RuntimeError>,
+    last: Option<Value>,
+    config: InterpreterConfig,
+    limits: Limits,
+    fuel: Option<u64>,
+    deadline: Option<Instant>,
+    steps: u64,
+    // Approximate bytes in use, only kept up to date under a memory limit.
+    memory: usize,
+    started: bool,
+    calls: Vec<Call>,
+    tracer: Option<SharedTracer>,
+    profiler: Option<Profiler>,
+    handlers: Vec<Handler>,
+    // An error a handler is about to catch.
+    caught: Option<RuntimeError>
 }
 
+// The clock and the cancel token are only looked at once in this many steps.
+const CHECK_INTERVAL: u64 = 256;
+
+// The work still to do. Evaluating an expression pushes the tasks that finish
+// it, and every task that produces something leaves it on the value stack.
+enum Task {
+    Eval(Expr),
+    Apply { callee: Callee, argc: usize, span: Span },
+    Return,
+    Bind(Symbol),
+    // The span is the condition's, for the error when it is not a bool.
+    Branch { then: Expr, otherwise: Expr, form: Symbol, span: Span },
+    MakeList(usize),
+    // Leaves the innermost try, running its finally.
+    EndTry,
+    Pop,
+    Rethrow(Box<RuntimeError>)
+}
+
+// A try being evaluated, with how far to unwind when catching an error.
+struct Handler {
+    tasks: usize,
+    values: usize,
+    depth: usize,
+    calls: usize,
+    catch: Option<(Symbol, Expr)>,
+    finally: Option<Expr>,
+    span: Span
+}
+
+enum Callee {
+    Named(Symbol),
+    // The head was an expression and its value sits below the arguments.
+    Computed
+}
+
+// A call in progress and where it was called from. Lambdas called other
+// than by name are called lambda. Builtins only show up in backtraces,
+// as the call that failed.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Call {
+    pub name: Symbol,
+    pub span: Span,
+    pub builtin: bool
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum RuntimeErrorKind {
+    Builtin,
+    MalformedForm,
+    UnknownFunction,
+    Arity,
+    NotCallable,
+    InvalidCondition,
+    OutOfFuel,
+    StackOverflow,
+    TimedOut,
+    Cancelled,
+    MemoryLimitExceeded,
+    Thrown
+}
+
+impl RuntimeErrorKind {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            RuntimeErrorKind::Builtin => "builtin",
+            RuntimeErrorKind::MalformedForm => "malformed_form",
+            RuntimeErrorKind::UnknownFunction => "unknown_function",
+            RuntimeErrorKind::Arity => "arity",
+            RuntimeErrorKind::NotCallable => "not_callable",
+            RuntimeErrorKind::InvalidCondition => "invalid_condition",
+            RuntimeErrorKind::OutOfFuel => "out_of_fuel",
+            RuntimeErrorKind::StackOverflow => "stack_overflow",
+            RuntimeErrorKind::TimedOut => "timed_out",
+            RuntimeErrorKind::Cancelled => "cancelled",
+            RuntimeErrorKind::MemoryLimitExceeded => "memory_limit_exceeded",
+            RuntimeErrorKind::Thrown => "thrown"
+        }
+    }
+
+    // Whether try can catch it. Errors from limits can not be caught, nor
+    // do they run finally forms, so a script can not keep itself running.
+    pub fn is_catchable(&self) -> bool {
+        match *self {
+            RuntimeErrorKind::OutOfFuel | RuntimeErrorKind::StackOverflow | RuntimeErrorKind::TimedOut |
+            RuntimeErrorKind::Cancelled | RuntimeErrorKind::MemoryLimitExceeded => false,
+            _ => true
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct RuntimeError {
+    pub kind: RuntimeErrorKind,
+    pub message: String,
+    pub span: Option<Span>,
+    // The calls in progress when the error happened, outermost first.
+    pub backtrace: Vec<Call>,
+    // The value given to throw, for Thrown errors. Boxed to keep errors
+    // small.
+    pub thrown: Option<Box<Value>>
+}
+
+impl RuntimeError {
+    pub fn new(kind: RuntimeErrorKind, message: String, span: Option<Span>) -> Self {
+        RuntimeError {
+            kind: kind,
+            message: message,
+            span: span,
+            backtrace: Vec::new(),
+            thrown: None
+        }
+    }
+
+    // What a catch handler is given: the thrown value, or for other errors
+    // a list of the kind's name and the message, such as
+    // <arity "`f` expects 1 argument(s), found 2">.
+    pub fn to_value(&self) -> Value {
+        match self.thrown {
+            Some(ref val) => (**val).clone(),
+            None => Value::List(vec![Value::Symbol(self.kind.name().to_string()), Value::Str(self.message.clone())])
+        }
+    }
+
+    pub fn with_backtrace(mut self, backtrace: Vec<Call>) -> Self {
+        self.backtrace = backtrace;
+        self
+    }
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl Error for RuntimeError {}
+
 pub trait EvalTrait {
-    fn eval(&mut self);
+    fn eval(&mut self) -> Result<(), RuntimeError>;
     fn is_evaluated(&self) -> bool;
 }
 
 pub trait PrettyPrint {
     fn pretty_print(&mut self) -> String;
-    fn push_whitespace(&self, pretty: &mut String);
 }
 
 impl PrettyPrint for Eval {
-    fn push_whitespace(&self, pretty: &mut String) {
-        for _ in 0..self.stack.len() {
-            pretty.push('\t');
-        }
-    }
-
     fn pretty_print(&mut self) -> String {
-        let mut pretty = String::new();
-        loop {
-            match self.stack.pop() {
-                Some(mut current) => {
-                    match current.0.get_child(0) {
-                        Some(mut child) => {
-                            self.stack.push(current);
-                            if child.node_val.is_some() {
-                                let tok = child.node_val.unwrap();
-                                if tok.tok_type == Type::Oparen {
-                                    self.push_whitespace(&mut pretty);
-                                    pretty.push('(');
-                                    pretty.push('\n');
-                                } else if tok.tok_type == Type::OpenList {
-                                    self.push_whitespace(&mut pretty);
-                                    pretty.push('<');
-                                    pretty.push('\n');
-                                }
-                                child.node_val = Some(tok);
-                            }
-                            self.stack.push((child, 0));
-                        },
-                        None => {
-                            if current.0.node_val.is_some() {
-                                let tok = current.0.node_val.unwrap();
-                                self.push_whitespace(&mut pretty);
-                                match tok.tok_type {
-                                    Type::Oparen => pretty.push(')'),
-                                    Type::OpenList => pretty.push('>'),
-                                    _ => pretty.push_str(&tok.get_lexed())
-                                }
-                            }
-                            pretty.push('\n');
-                        }
-                    }
-                },
-                None => {
-                    match self.ast.get_child(0) {
-                        Some(mut child) => {
-                            if child.node_val.is_some() {
-                                let tok = child.node_val.unwrap();
-                                if tok.tok_type == Type::Oparen {
-                                    self.push_whitespace(&mut pretty);
-                                    pretty.push('(');
-                                    pretty.push('\n');
-                                } else if tok.tok_type == Type::OpenList {
-                                    self.push_whitespace(&mut pretty);
-                                    pretty.push('<');
-                                    pretty.push('\n');
-                                }
-                                child.node_val = Some(tok);
-                            }
-                            self.stack.push((child, 0));
-                        },
-                        None => break
-                    }
-                }
-            }
-            if self.stack.is_empty() && self.ast.child_count() == 0 {
-                break;
-            }
-        }
-        pretty
+        ast::pretty(&self.ast, ast::DEFAULT_WIDTH)
     }
 }
 
 impl EvalTrait for Eval {
-    fn eval(&mut self) {
-        let mut scope = Scope::new_root();
-        while !self.evaluated {
-            scope = self.eval_node(scope);
-        }
+    fn eval(&mut self) -> Result<(), RuntimeError> {
+        self.eval_with_scope(Scope::new_root()).1.map(|_| ())
     }
 
     fn is_evaluated(&self) -> bool {
@@ -108,273 +205,814 @@ impl EvalTrait for Eval {
 
 impl Eval {
     pub fn new(ast: Ast) -> Self {
-        Eval {
-            ast: ast,
-            stack: Vec::new(),
-            evaluated: false
+        let mut eval = Eval::from_exprs(Vec::new());
+        match expr::lower(&ast) {
+            Ok(mut program) => {
+                program.reverse();
+                eval.program = program;
+            },
+            Err(err) => eval.fail(RuntimeErrorKind::MalformedForm, err.message, Some(err.span))
         }
+        eval.ast = ast;
+        eval
     }
 
     pub fn new_option(ast: Option<Ast>) -> Self {
         match ast {
-            Some(tree) => Eval {
-                ast: tree,
-                stack: Vec::new(),
-                evaluated: false
-            },
+            Some(tree) => Eval::new(tree),
             None => panic!()
         }
     }
 
-    fn expand_function(&mut self, scope: &mut Scope) -> bool {
-        match self.stack.pop() {
-            Some(mut current) => {
-                match current.0.is_function() {
-                    true => {
-                        let mut child = current.0.get_child(0).unwrap();
-                        let node_val = child.node_val.unwrap();
-                        match scope.find_func(&node_val.get_lexed()) {
-                            Some(func) => {
-                                if !self.inject_params(func, &mut current.0) {
-                                    current.0 = func.body.clone();
-                                }
-                                self.stack.push(current);
-                                true
-                            },
-                            None => {
-                                child.node_val = Some(node_val);
-                                current.0.insert_child(child, 0);
-                                self.stack.push(current);
-                                false
-                            }
-                        }
-                    },
-                    false => {
-                        self.stack.push(current);
-                        false
-                    }
-                }
-            },
-            None => false
+    pub fn from_exprs(mut program: Vec<Expr>) -> Self {
+        program.reverse();
+        Eval {
+            ast: Ast::new_null(),
+            program: program,
+            tasks: Vec::new(),
+            values: Vec::new(),
+            depth: 0,
+            evaluated: false,
+            error: None,
+            last: None,
+            config: InterpreterConfig::new(),
+            limits: Limits::new(),
+            fuel: None,
+            deadline: None,
+            steps: 0,
+            memory: 0,
+            started: false,
+            calls: Vec::new(),
+            tracer: None,
+            profiler: None,
+            handlers: Vec::new(),
+            caught: None
+        }
+    }
+
+    // Only the builtins the config allows can be called. Its limits replace
+    // any set before.
+    pub fn set_config(&mut self, config: &InterpreterConfig) {
+        self.config = config.clone();
+        self.set_limits(config.limits.clone());
+    }
+
+    // Limits apply from the next step, with a full tank of fuel and the
+    // timeout counted from now.
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.fuel = limits.fuel;
+        self.deadline = limits.timeout.map(|timeout| Instant::now() + timeout);
+        self.limits = limits;
+    }
+
+    pub fn set_deadline(&mut self, deadline: Instant) {
+        self.deadline = Some(deadline);
+    }
+
+    pub fn set_tracer(&mut self, tracer: Option<SharedTracer>) {
+        self.tracer = tracer;
+    }
+
+    // Counts into the profiler from the next step. Take it back once the
+    // program is finished to read the counts.
+    pub fn set_profiler(&mut self, profiler: Option<Profiler>) {
+        self.profiler = profiler;
+    }
+
+    pub fn take_profiler(&mut self) -> Option<Profiler> {
+        self.profiler.take()
+    }
+
+    // Runs the program against an existing scope, handing the scope back so
+    // definitions carry over to the next program. The value is that of the
+    // last top level form.
+    pub fn eval_with_scope(&mut self, mut scope: Scope) -> (Scope, Result<Value, RuntimeError>) {
+        while !self.evaluated {
+            scope = self.step(scope);
         }
+        self.finish(scope)
     }
 
-    fn inject_params(&mut self, func: &Func, current: &mut Ast) -> bool {
-        let mut current_children = current.dump_children();
-        let func_children = func.params.clone_children();
-        if func_children.is_empty() {
-            false
-        } else {
-            for child in func_children.into_iter() {
-                current.push_child(builtins::generate_let_ast(child.node_val.unwrap(), current_children.remove(0)));
+    // Does one task of the program, for hosts that want to stop between
+    // tasks. Keep stepping with the scope handed back until is_evaluated,
+    // then call finish.
+    pub fn step(&mut self, mut scope: Scope) -> Scope {
+        if !self.started {
+            self.started = true;
+            // Parsing the program interned its names on this thread.
+            scope.names += intern::take_uncharged();
+            if self.is_accounting() {
+                self.memory = scope.size();
+                let pending = self.program.iter().map(Expr::size).sum::<usize>() as isize;
+                self.charge(pending);
             }
-            current.push_child(func.body.clone());
-            true
-        }
-    }
-
-    fn handle_builtin(&mut self, mut parent: (Ast, usize)) {
-        let result = builtins::evaluate_builtin(parent.0);
-        if result.is_push() {
-            self.stack.push((result.unwrap_push(), parent.1));
-        } else if result.is_error() {
-            self.evaluated = true;
-        } else if result.is_insert() {
-            match self.stack.pop() {
-                Some(mut new_parent) => {
-                    parent.0 = result.unwrap_insert();
-                    let new_index = parent.1 + 1;
-                    new_parent.0.insert_child(parent.0, parent.1);
-                    let new = (new_parent.0.get_child(new_index).unwrap(), new_index);
-                    self.stack.push(new_parent);
-                    self.stack.push(new);
-                },
-                None => self.evaluated = true
+        }
+        match self.evaluated {
+            true => scope,
+            false => self.eval_node(scope)
+        }
+    }
+
+    // Leaves any calls still in progress, as after an error, and gives back
+    // the scope the program started with along with its value.
+    pub fn finish(&mut self, mut scope: Scope) -> (Scope, Result<Value, RuntimeError>) {
+        while self.depth > 0 {
+            scope = scope.into_parent();
+            self.depth -= 1;
+        }
+        self.calls.clear();
+        self.handlers.clear();
+        self.caught = None;
+        if let Some(ref mut profiler) = self.profiler {
+            profiler.unwind_to(0);
+        }
+        let result = match self.error.take() {
+            Some(err) => Err(err),
+            None => Ok(self.last.take().unwrap_or(Value::Nil))
+        };
+        (scope, result)
+    }
+
+    // How many user function calls are in progress.
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    pub fn calls(&self) -> &[Call] {
+        &self.calls
+    }
+
+    // Values computed but not used yet, such as the arguments of a call whose
+    // last argument is still being evaluated.
+    pub fn values(&self) -> &[Value] {
+        &self.values
+    }
+
+    // The form the next step evaluates, if it evaluates anything other than
+    // a literal or a name.
+    pub fn next_form(&self) -> Option<&Expr> {
+        match self.tasks.last() {
+            Some(Task::Eval(Expr::Literal(..))) | Some(Task::Eval(Expr::Symbol(..))) => None,
+            Some(Task::Eval(expr)) => Some(expr),
+            _ => None
+        }
+    }
+
+    // What is left to do, the next task first, ending with the top level
+    // forms not started yet.
+    pub fn pending(&self) -> Vec<String> {
+        let tasks = self.tasks.iter().rev().map(|task| task.to_string());
+        let forms = self.program.iter().rev().map(|expr| format!("eval {}", describe(expr)));
+        tasks.chain(forms).collect()
+    }
+
+    fn fail(&mut self, kind: RuntimeErrorKind, message: String, span: Option<Span>) {
+        let span = match span {
+            Some(span) if !span.is_dummy() => Some(span),
+            _ => None
+        };
+        let err = RuntimeError::new(kind, message, span).with_backtrace(self.calls.clone());
+        self.raise(err);
+    }
+
+    // Hands the error to the innermost try, or stops evaluation with it.
+    fn raise(&mut self, err: RuntimeError) {
+        let depth = self.depth;
+        self.trace(|tracer| tracer.error(&err, depth));
+        match err.kind.is_catchable() && !self.handlers.is_empty() {
+            true => self.caught = Some(err),
+            false => {
+                self.error = Some(err);
+                self.tasks.clear();
+                self.values.clear();
+                self.evaluated = true;
             }
         }
     }
 
-    fn handle_function(&mut self, scope: &mut Scope) {
-        if !self.expand_function(scope) {
-            match self.stack.pop() {
-                Some(mut parent) => {
-                    let result = builtins::evaluate_set_funcs(parent.0);
-                    if result.is_skip() {
-                        parent.0 = result.unwrap_skip();
-                        match parent.0.get_child(1) {
-                            Some(new_child) => {
-                                self.stack.push(parent);
-                                self.stack.push((new_child, 1));
-                            },
-                            None => self.handle_builtin(parent)
-                        }
-                    } else if result.is_remove() {
-                        parent.0 = result.unwrap_remove();
-                        match self.stack.pop() {
-                            Some(mut func_head) => {
-                                let new_index = parent.1 + 1;
-                                func_head.0.insert_child(parent.0, parent.1);
-                                match func_head.0.get_child(new_index) {
-                                    Some(new_ast) => {
-                                        self.stack.push(func_head);
-                                        self.stack.push((new_ast, new_index));
-                                    },
-                                    None => self.stack.push(func_head)
-                                }
-                            },
-                            None => self.evaluated = true
-                        }
-                    } else if result.is_other() {
-                        let set_result = builtins::evaluate_set(result.unwrap_other(), scope);
-                        if set_result.is_insert() {
-                            parent.0 = set_result.unwrap_insert();
-                            match parent.0.get_child(1) {
-                                Some(new_child) => {
-                                    self.stack.push(parent);
-                                    self.stack.push((new_child, 1));
-                                },
-                                None => self.handle_builtin(parent)
-                            }
-                        }
-                    } else {
-                        self.evaluated = true;
-                    }
-                },
-                None => self.evaluated = true
+    // Unwinds to the innermost try and runs its catch or finally.
+    fn catch(&mut self, err: RuntimeError, mut scope: Scope) -> Scope {
+        let handler = self.handlers.pop().unwrap();
+        while self.tasks.len() > handler.tasks {
+            let task = self.tasks.pop();
+            if self.is_accounting() {
+                self.charge(-(task.as_ref().map_or(0, Task::size) as isize));
+            }
+        }
+        let count = self.values.len() - handler.values;
+        self.take_values(count);
+        while self.depth > handler.depth {
+            if self.is_accounting() {
+                self.charge(-(scope.own_size() as isize));
+            }
+            scope = scope.into_parent();
+            self.depth -= 1;
+        }
+        self.calls.truncate(handler.calls);
+        if let Some(ref mut profiler) = self.profiler {
+            profiler.unwind_to(handler.calls);
+        }
+        match handler.catch {
+            Some((param, body)) => {
+                // An error in the handler still runs the finally.
+                if let Some(finally) = handler.finally {
+                    self.handlers.push(Handler {
+                        tasks: self.tasks.len(),
+                        values: self.values.len(),
+                        depth: self.depth,
+                        calls: self.calls.len(),
+                        catch: None,
+                        finally: Some(finally),
+                        span: handler.span
+                    });
+                    self.push_task(Task::EndTry);
+                }
+                let catch = Func::new(Symbol::intern("catch"), vec![param], body);
+                self.call(catch.get_name(), catch, vec![err.to_value()], handler.span, scope)
+            },
+            None => {
+                self.push_task(Task::Rethrow(Box::new(err)));
+                self.push_task(Task::Pop);
+                self.push_task(Task::Eval(handler.finally.unwrap()));
+                scope
             }
         }
     }
 
-    fn handle_new_node(&mut self, mut scope: Scope, mut child: Ast) -> Scope {
-        if child.node_val.is_some() {
-            let tok = child.node_val.unwrap();
-            if tok.tok_type == Type::Func {
-                child.node_val = Some(tok);
-                match self.stack.pop() {
-                    Some(mut parent) => {
-                        parent.0.insert_child(child, 0);
-                        self.stack.push(parent);
+    fn trace<F: FnOnce(&mut dyn Tracer)>(&self, event: F) {
+        if let Some(ref tracer) = self.tracer {
+            event(&mut *tracer.borrow_mut());
+        }
+    }
+
+    // Takes cost from the fuel, failing if there is not enough left.
+    fn burn(&mut self, cost: u64, span: Option<Span>) -> bool {
+        match self.fuel {
+            Some(fuel) if fuel < cost => {
+                let message = format!("ran out of fuel after {} steps", self.limits.fuel.unwrap_or(0));
+                self.fail(RuntimeErrorKind::OutOfFuel, message, span);
+                false
+            },
+            Some(fuel) => {
+                self.fuel = Some(fuel - cost);
+                true
+            },
+            None => true
+        }
+    }
+
+    fn is_accounting(&self) -> bool {
+        self.limits.max_memory.is_some()
+    }
+
+    // Adds bytes to the memory in use, or takes them off when negative.
+    fn charge(&mut self, bytes: isize) {
+        self.memory = (self.memory as isize + bytes).max(0) as usize;
+        match self.limits.max_memory {
+            Some(max) if bytes > 0 && self.memory > max && !self.evaluated => {
+                let message = format!("memory limit of {} bytes exceeded", max);
+                self.fail(RuntimeErrorKind::MemoryLimitExceeded, message, None);
+            },
+            _ => ()
+        }
+    }
+
+    fn push_value(&mut self, val: Value) {
+        if self.is_accounting() {
+            self.charge(val.size() as isize);
+        }
+        self.values.push(val);
+    }
+
+    fn pop_value(&mut self) -> Option<Value> {
+        let val = self.values.pop();
+        if self.is_accounting() {
+            self.charge(-(val.as_ref().map_or(0, Value::size) as isize));
+        }
+        val
+    }
+
+    fn take_values(&mut self, count: usize) -> Vec<Value> {
+        let at = self.values.len() - count;
+        let vals = self.values.split_off(at);
+        if self.is_accounting() {
+            self.charge(-(vals.iter().map(Value::size).sum::<usize>() as isize));
+        }
+        vals
+    }
+
+    fn push_task(&mut self, task: Task) {
+        if self.is_accounting() {
+            self.charge(task.size() as isize);
+        }
+        self.tasks.push(task);
+    }
+
+    fn push_tasks<I: DoubleEndedIterator<Item = Task>>(&mut self, tasks: I) {
+        for task in tasks.rev() {
+            self.push_task(task);
+        }
+    }
+
+    fn define(&mut self, scope: &mut Scope, func: Func) {
+        let bytes = define(scope, func);
+        if self.is_accounting() {
+            self.charge(bytes);
+        }
+    }
+
+    fn check_interrupts(&mut self, span: Option<Span>) -> bool {
+        self.steps += 1;
+        if !self.steps.is_multiple_of(CHECK_INTERVAL) {
+            return true;
+        }
+        if self.limits.cancel.as_ref().is_some_and(|cancel| cancel.load(Ordering::Relaxed)) {
+            self.fail(RuntimeErrorKind::Cancelled, "evaluation was cancelled".to_string(), span);
+            return false;
+        }
+        if self.deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            let message = match self.limits.timeout {
+                Some(timeout) => format!("timed out after {} ms", timeout.as_millis()),
+                None => "passed its deadline".to_string()
+            };
+            self.fail(RuntimeErrorKind::TimedOut, message, span);
+            return false;
+        }
+        true
+    }
+
+    fn eval_node(&mut self, mut scope: Scope) -> Scope {
+        let task = self.tasks.pop();
+        if self.is_accounting() {
+            self.charge(-(task.as_ref().map_or(0, Task::size) as isize));
+        }
+        let span = match task {
+            Some(Task::Eval(ref expr)) => Some(expr.span()),
+            Some(Task::Apply { span, .. }) => Some(span),
+            _ => None
+        };
+        if !self.burn(1, span) || !self.check_interrupts(span) {
+            return scope;
+        }
+        if let Some(ref mut profiler) = self.profiler {
+            profiler.step();
+        }
+        match task {
+            Some(Task::Eval(expr)) => self.eval_expr(expr, &mut scope),
+            Some(Task::Apply { callee, argc, span }) => scope = self.apply(callee, argc, span, scope),
+            Some(Task::Return) => {
+                if self.is_accounting() {
+                    self.charge(-(scope.own_size() as isize));
+                }
+                scope = scope.into_parent();
+                self.depth -= 1;
+                let call = self.calls.pop().unwrap();
+                if let Some(ref mut profiler) = self.profiler {
+                    profiler.exit();
+                }
+                let depth = self.depth;
+                self.trace(|tracer| tracer.exit(call.name, self.values.last().unwrap_or(&Value::Nil), depth));
+            },
+            Some(Task::Bind(name)) => {
+                let val = self.pop_value().unwrap();
+                let depth = self.depth;
+                self.trace(|tracer| tracer.special_form(&SpecialForm::Let(name, &val), depth));
+                self.define(&mut scope, Func::new(name, Vec::new(), Expr::Literal(val, Span::default())));
+                self.push_value(Value::Nil);
+            },
+            Some(Task::Branch { then, otherwise, form, span }) => {
+                let cond = self.pop_value().unwrap();
+                let depth = self.depth;
+                match cond.as_bool() {
+                    Some(truth) => {
+                        self.trace(|tracer| tracer.special_form(&SpecialForm::If(truth), depth));
+                        self.push_task(Task::Eval(match truth {
+                            true => then,
+                            false => otherwise
+                        }));
+                    },
+                    None => {
+                        let message = format!("`{}` condition must be \"true\" or \"false\", found `{}`", form, cond);
+                        self.fail(RuntimeErrorKind::InvalidCondition, message, Some(span));
+                    }
+                }
+            },
+            Some(Task::MakeList(len)) => {
+                let items = self.take_values(len);
+                self.push_value(Value::List(items));
+            },
+            Some(Task::EndTry) => {
+                let handler = self.handlers.pop().unwrap();
+                if let Some(finally) = handler.finally {
+                    self.push_task(Task::Pop);
+                    self.push_task(Task::Eval(finally));
+                }
+            },
+            Some(Task::Pop) => {
+                self.pop_value();
+            },
+            Some(Task::Rethrow(err)) => self.raise(*err),
+            None => {
+                if let Some(val) = self.pop_value() {
+                    self.last = Some(val);
+                }
+                match self.program.pop() {
+                    Some(expr) => {
+                        // Moving from the program to the tasks costs nothing.
+                        if self.is_accounting() {
+                            self.charge(-(expr.size() as isize));
+                        }
+                        self.push_task(Task::Eval(expr));
                     },
                     None => self.evaluated = true
                 }
-                self.handle_function(&mut scope);
-            } else if tok.tok_type == Type::Oparen {
-                child.node_val = Some(tok);
-                self.stack.push((child, 0));
-                scope = Scope::new(scope);
-            } else {
-                child.node_val = Some(tok);
-                self.stack.push((child, 0));
             }
-        } else {
-            self.stack.push((child, 0));
         }
-        scope
+        match self.caught.take() {
+            Some(err) => self.catch(err, scope),
+            None => scope
+        }
     }
 
-    fn eval_node(&mut self, mut scope: Scope) -> Scope {
-        match self.stack.pop() {
-            Some(mut current) => {
-                match current.0.get_child(0) {
-                    Some(child) => {
-                        self.stack.push(current);
-                        scope = self.handle_new_node(scope, child);
-                    },
+    fn eval_expr(&mut self, expr: Expr, scope: &mut Scope) {
+        match expr {
+            Expr::Literal(val, _) => self.push_value(val),
+            Expr::Symbol(name, _) => self.push_value(Value::Symbol(name.as_str().to_string())),
+            Expr::List(items, _) => {
+                self.push_task(Task::MakeList(items.len()));
+                self.push_tasks(items.into_iter().map(Task::Eval));
+            },
+            Expr::Set { name, params, body, .. } => {
+                let depth = self.depth;
+                self.trace(|tracer| tracer.special_form(&SpecialForm::Set(name), depth));
+                self.define(scope, Func::new(name, params, *body));
+                self.push_value(Value::Nil);
+            },
+            Expr::Let { name, body, .. } => {
+                self.push_task(Task::Bind(name));
+                self.push_task(Task::Eval(*body));
+            },
+            Expr::Lambda { params, body, .. } => {
+                self.push_value(Value::Lambda(Rc::new(Func::new(Symbol::intern("lambda"), params, *body))));
+            },
+            Expr::If { cond, then, otherwise, form, .. } => {
+                self.push_task(Task::Branch { then: *then, otherwise: *otherwise, form: form, span: cond.span() });
+                self.push_task(Task::Eval(*cond));
+            },
+            Expr::Try { body, catch, finally, span } => {
+                self.handlers.push(Handler {
+                    tasks: self.tasks.len(),
+                    values: self.values.len(),
+                    depth: self.depth,
+                    calls: self.calls.len(),
+                    catch: catch.map(|(param, body)| (param, *body)),
+                    finally: finally.map(|finally| *finally),
+                    span: span
+                });
+                self.push_task(Task::EndTry);
+                self.push_task(Task::Eval(*body));
+            },
+            Expr::Do(mut forms, _) => {
+                match forms.pop() {
+                    Some(last) => self.push_task(Task::Eval(last)),
+                    None => self.push_value(Value::Nil)
+                }
+                // Every value but the last is dropped once evaluated.
+                for form in forms.into_iter().rev() {
+                    self.push_task(Task::Pop);
+                    self.push_task(Task::Eval(form));
+                }
+            },
+            Expr::Call { head, args, span } => {
+                let (callee, computed) = match *head {
+                    Expr::Symbol(name, _) => (Callee::Named(name), None),
+                    head => (Callee::Computed, Some(head))
+                };
+                self.push_task(Task::Apply { callee: callee, argc: args.len(), span: span });
+                self.push_tasks(args.into_iter().map(Task::Eval));
+                if let Some(head) = computed {
+                    self.push_task(Task::Eval(head));
+                }
+            }
+        }
+    }
+
+    fn apply(&mut self, callee: Callee, argc: usize, span: Span, mut scope: Scope) -> Scope {
+        let args = self.take_values(argc);
+        let mut named = None;
+        let func = match callee {
+            Callee::Named(name) => {
+                named = Some(name);
+                match scope.find_func(name) {
+                    Some(func) => resolve(func, argc),
                     None => {
-                        match self.stack.pop() {
-                            Some(mut parent) => {
-                                let new_index = current.1 + 1;
-                                parent.0.insert_child(current.0, current.1);
-                                if parent.0.child_count() > new_index {
-                                    let new_child = parent.0.get_child(new_index);
-                                    self.stack.push(parent);
-                                    self.stack.push((new_child.unwrap(), new_index));
-                                } else {
-                                    self.handle_builtin(parent);
-                                }
+                        if !self.burn(1, Some(span)) {
+                            return scope;
+                        }
+                        // Only calls that happen are traced.
+                        if !self.config.has_builtin(name.as_str()) {
+                            let message = format!("unknown function `{}`", name);
+                            self.fail(RuntimeErrorKind::UnknownFunction, message, Some(span));
+                            return scope;
+                        }
+                        let depth = self.depth;
+                        self.trace(|tracer| tracer.enter(name, &args, depth));
+                        let result = self.config.call_builtin(name.as_str(), &args);
+                        if let Some(ref mut profiler) = self.profiler {
+                            profiler.enter(name, true);
+                            profiler.step();
+                            profiler.exit();
+                        }
+                        match result {
+                            Some(Ok(val)) => {
+                                self.trace(|tracer| tracer.exit(name, &val, depth));
+                                self.push_value(val);
                             },
-                            None => self.evaluated = true
+                            Some(Err(err)) => {
+                                self.calls.push(Call {
+                                    name: name,
+                                    span: span,
+                                    builtin: true
+                                });
+                                let err = builtin_error(err, Some(span)).with_backtrace(self.calls.clone());
+                                self.raise(err);
+                            },
+                            None => {
+                                let message = format!("unknown function `{}`", name);
+                                self.fail(RuntimeErrorKind::UnknownFunction, message, Some(span));
+                            }
                         }
+                        return scope;
                     }
                 }
             },
-            None => {
-                match self.ast.get_child(0) {
-                    Some(child) => scope = self.handle_new_node(scope, child),
-                    None => self.evaluated = true
+            Callee::Computed => {
+                match self.pop_value() {
+                    Some(Value::Lambda(func)) => (*func).clone(),
+                    other => {
+                        let message = format!("`{}` can not be called", other.unwrap_or(Value::Nil));
+                        self.fail(RuntimeErrorKind::NotCallable, message, Some(span));
+                        return scope;
+                    }
                 }
             }
+        };
+        if func.params.len() != argc {
+            let message = format!("`{}` expects {} argument(s), found {}", func.get_name(), func.params.len(), argc);
+            self.fail(RuntimeErrorKind::Arity, message, Some(span));
+            return scope;
+        }
+        // Params and lets are read without a call of their own.
+        if let Expr::Literal(ref val, _) = func.body {
+            if argc == 0 {
+                self.push_value(val.clone());
+                return scope;
+            }
+        }
+        self.call(named.unwrap_or(func.get_name()), func, args, span, scope)
+    }
+
+    // Enters a user function, its params bound to args in a scope of its own.
+    fn call(&mut self, name: Symbol, func: Func, args: Vec<Value>, span: Span, mut scope: Scope) -> Scope {
+        if self.limits.max_depth.is_some_and(|max| self.depth >= max) {
+            let message = format!("stack overflow: more than {} nested calls", self.depth);
+            self.fail(RuntimeErrorKind::StackOverflow, message, Some(span));
+            return scope;
+        }
+
+        let depth = self.depth;
+        self.trace(|tracer| tracer.enter(name, &args, depth));
+        if let Some(ref mut profiler) = self.profiler {
+            profiler.enter(name, false);
         }
-        if self.stack.is_empty() && self.ast.child_count() == 0 {
-            self.evaluated = true;
+        scope = Scope::new(scope);
+        self.depth += 1;
+        self.calls.push(Call {
+            name: name,
+            span: span,
+            builtin: false
+        });
+        for (param, arg) in func.params.iter().zip(args) {
+            self.define_local(&mut scope, Func::new(*param, Vec::new(), Expr::Literal(arg, span)));
         }
+        self.push_task(Task::Return);
+        self.push_task(Task::Eval(func.body));
         scope
     }
+
+    fn define_local(&mut self, scope: &mut Scope, func: Func) {
+        if self.is_accounting() {
+            self.charge(func.size() as isize);
+        }
+        scope.insert_func_no_search(func);
+    }
 }
 
-pub struct Scope {
-    pub parent: Option<Box<Scope>>,
-    funcs: Vec<Func>,
-    pub evaluate_scope: bool
+// The runtime error for a failed builtin call.
+pub fn builtin_error(err: BuiltinError, span: Option<Span>) -> RuntimeError {
+    match err {
+        BuiltinError::Failed(message) => RuntimeError::new(RuntimeErrorKind::Builtin, message, span),
+        BuiltinError::Thrown(val) => {
+            let mut err = RuntimeError::new(RuntimeErrorKind::Thrown, format!("uncaught throw of `{}`", val), span);
+            err.thrown = Some(Box::new(val));
+            err
+        }
+    }
 }
 
-impl Scope {
-    pub fn new(parent: Scope) -> Self
-    {
-        Scope {
-            parent: Some(Box::new(parent)),
-            funcs: Vec::new(),
-            evaluate_scope: false
+// A let bound to a lambda is called through its value.
+fn resolve(func: &Func, argc: usize) -> Func {
+    if func.params.is_empty() && argc > 0 {
+        if let Expr::Literal(Value::Lambda(ref lambda), _) = func.body {
+            return (**lambda).clone();
+        }
+    }
+    func.clone()
+}
+
+// Redefines the nearest function with func's name, or adds it to the
+// innermost scope. Returns how many bytes the scope grew by.
+fn define(scope: &mut Scope, func: Func) -> isize {
+    let size = func.size() as isize;
+    match scope.find_func(func.get_name()) {
+        Some(existing) => {
+            let old = existing.size() as isize;
+            existing.reset(func.params, func.body);
+            size - old
+        },
+        None => {
+            scope.insert_func_no_search(func);
+            size
+        }
+    }
+}
+
+// A short description of a form, for showing where evaluation is.
+pub fn describe(expr: &Expr) -> String {
+    match *expr {
+        Expr::Literal(ref val, _) => val.to_string(),
+        Expr::Symbol(name, _) => name.to_string(),
+        Expr::List(ref items, _) => format!("list of {}", items.len()),
+        Expr::Set { name, .. } => format!("(set {})", name),
+        Expr::Let { name, .. } => format!("(let {})", name),
+        Expr::Lambda { .. } => "(lambda)".to_string(),
+        Expr::If { .. } => "(if)".to_string(),
+        Expr::Try { .. } => "(try)".to_string(),
+        Expr::Do(ref forms, _) => format!("(do ...{})", forms.len()),
+        Expr::Call { ref head, ref args, .. } => {
+            match **head {
+                Expr::Symbol(name, _) => format!("({} ...{})", name, args.len()),
+                _ => format!("(... ...{})", args.len())
+            }
+        }
+    }
+}
+
+impl fmt::Display for Task {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Task::Eval(ref expr) => write!(f, "eval {}", describe(expr)),
+            Task::Apply { callee: Callee::Named(name), argc, .. } => write!(f, "call {} with {} argument(s)", name, argc),
+            Task::Apply { argc, .. } => write!(f, "call value with {} argument(s)", argc),
+            Task::Return => write!(f, "return"),
+            Task::Bind(name) => write!(f, "bind {}", name),
+            Task::Branch { .. } => write!(f, "choose branch"),
+            Task::MakeList(len) => write!(f, "make list of {}", len),
+            Task::EndTry => write!(f, "leave try"),
+            Task::Pop => write!(f, "drop value"),
+            Task::Rethrow(ref err) => write!(f, "rethrow `{}`", err)
+        }
+    }
+}
+
+impl Task {
+    fn size(&self) -> usize {
+        mem::size_of::<Task>() + match *self {
+            Task::Eval(ref expr) => expr.size(),
+            Task::Branch { ref then, ref otherwise, .. } => then.size() + otherwise.size(),
+            _ => 0
         }
     }
+}
+
+// Every definition in reach, as a stack of them per name, so a lookup
+// costs the same however deep the calls go. Each call opens a level of its
+// own, and leaving the level undoes the definitions made in it.
+#[derive(Clone)]
+pub struct Scope {
+    bindings: SymbolMap<Vec<Binding>>,
+    // The names defined at each level, in the order they were defined.
+    log: Vec<Symbol>,
+    // Where in the log each level above the root starts.
+    levels: Vec<usize>,
+    // Bytes of the names interned for programs run against the scope, which
+    // are never freed.
+    names: usize
+}
+
+#[derive(Clone)]
+struct Binding {
+    level: usize,
+    func: Func
+}
 
+impl Scope {
+    // Opens a level for a call inside parent.
+    pub fn new(mut parent: Scope) -> Self {
+        parent.levels.push(parent.log.len());
+        parent
+    }
 
     pub fn new_root() -> Self {
         Scope {
-            parent: None,
-            funcs: Vec::new(),
-            evaluate_scope: false
+            bindings: SymbolMap::default(),
+            log: Vec::new(),
+            levels: Vec::new(),
+            names: 0
         }
     }
 
-    pub fn insert_func(&mut self, func: Func) {
-        if self.find_func(func.get_name()).is_none() {
-            self.insert_func_no_search(func);
+    // Leaves the innermost level, dropping what it defined. The root stays
+    // as it is.
+    pub fn into_parent(mut self) -> Scope {
+        if let Some(start) = self.levels.pop() {
+            for name in self.log.drain(start..) {
+                if let Some(bindings) = self.bindings.get_mut(&name) {
+                    bindings.pop();
+                }
+            }
         }
+        self
     }
 
+    // How many levels are open above the root.
+    pub fn depth(&self) -> usize {
+        self.levels.len()
+    }
+
+    // Adds func to the innermost level unless it already has one by its
+    // name.
     pub fn insert_func_no_search(&mut self, func: Func) {
-        let loc = self.funcs.binary_search(&func);
-        if loc.is_err() {
-            self.funcs.insert(loc.unwrap_err(), func);
+        let level = self.depth();
+        let bindings = self.bindings.entry(func.get_name()).or_default();
+        if bindings.last().is_some_and(|binding| binding.level == level) {
+            return;
         }
+        self.log.push(func.get_name());
+        bindings.push(Binding {
+            level: level,
+            func: func
+        });
     }
 
-    pub fn find_func(&mut self, tok_str: &String) -> Option<&mut Func> {
-        let loc = self.funcs.binary_search_by(|func| {
-            tok_str.cmp(func.get_name())
-        });
-        match loc {
-            Ok(index) => Some(self.funcs.index_mut(index)),
-            _ => {
-                match self.parent {
-                    Some(ref mut parent) => parent.find_func(tok_str),
-                    None => None
-                }
+    // Roughly how many bytes the innermost level's definitions take up.
+    pub fn own_size(&self) -> usize {
+        self.funcs().iter().map(|func| func.size()).sum()
+    }
+
+    // The same, for every level, along with the names interned for the
+    // programs run against it.
+    pub fn size(&self) -> usize {
+        self.names + self.bindings.values().flat_map(|bindings| bindings.iter()).map(|binding| binding.func.size()).sum::<usize>()
+    }
+
+    // The innermost level's own definitions, sorted by name.
+    pub fn funcs(&self) -> Vec<&Func> {
+        self.funcs_at(self.depth())
+    }
+
+    // The definitions made at a level, 0 being the root, sorted by name.
+    pub fn funcs_at(&self, level: usize) -> Vec<&Func> {
+        let start = match level {
+            0 => 0,
+            _ => match self.levels.get(level - 1) {
+                Some(&start) => start,
+                None => return Vec::new()
             }
-        }
+        };
+        let end = self.levels.get(level).cloned().unwrap_or(self.log.len());
+        let mut funcs: Vec<&Func> = self.log[start..end].iter().filter_map(|name| {
+            self.bindings[name].iter().rev().find(|binding| binding.level == level).map(|binding| &binding.func)
+        }).collect();
+        funcs.sort_by_key(|func| func.get_name().as_str());
+        funcs
+    }
+
+    // The nearest definition, from the innermost level out.
+    pub fn find_func(&mut self, name: Symbol) -> Option<&mut Func> {
+        self.bindings.get_mut(&name).and_then(|bindings| bindings.last_mut()).map(|binding| &mut binding.func)
     }
 }
 
-#[derive(Eq, PartialEq, Ord, PartialOrd)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct Func {
-    name: String,
-    pub params: Ast,
-    pub body: Ast
+    name: Symbol,
+    pub params: Vec<Symbol>,
+    pub body: Expr
 }
 
 impl Func {
-    pub fn new(name: String, params: Ast, body: Ast) -> Self {
+    pub fn new(name: Symbol, params: Vec<Symbol>, body: Expr) -> Self {
         Func {
             name: name,
             params: params,
@@ -382,11 +1020,15 @@ impl Func {
         }
     }
 
-    pub fn get_name(&self) -> &String {
-        &self.name
+    pub fn get_name(&self) -> Symbol {
+        self.name
+    }
+
+    pub fn size(&self) -> usize {
+        mem::size_of::<Func>() + self.params.len() * mem::size_of::<Symbol>() + self.body.size()
     }
 
-    pub fn reset(&mut self, params: Ast, body: Ast) {
+    pub fn reset(&mut self, params: Vec<Symbol>, body: Expr) {
         self.params = params;
         self.body = body;
     }
@@ -0,0 +1,552 @@
+use ast::{Ast, AstTrait};
+use intern::Symbol;
+use std::error::Error;
+use std::fmt;
+use std::mem;
+use token::{self, Span, Token, TokenTrait, Type};
+use value::Value;
+
+// The shape of a form is worked out once here, so Eval never has to look
+// for "set" or "body" in a token tree.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Expr {
+    Call { head: Box<Expr>, args: Vec<Expr>, span: Span },
+    Set { name: Symbol, params: Vec<Symbol>, body: Box<Expr>, span: Span },
+    Let { name: Symbol, body: Box<Expr>, span: Span },
+    // The form is the one written, if, cond, when or unless, for errors to
+    // name.
+    If { cond: Box<Expr>, then: Box<Expr>, otherwise: Box<Expr>, form: Symbol, span: Span },
+    Lambda { params: Vec<Symbol>, body: Box<Expr>, span: Span },
+    // At least one of catch and finally is there. The catch is a handler
+    // called with the error value bound to its one param.
+    Try { body: Box<Expr>, catch: Option<(Symbol, Box<Expr>)>, finally: Option<Box<Expr>>, span: Span },
+    // Evaluates each form in order, the value being that of the last.
+    Do(Vec<Expr>, Span),
+    List(Vec<Expr>, Span),
+    Literal(Value, Span),
+    Symbol(Symbol, Span)
+}
+
+impl Expr {
+    pub fn span(&self) -> Span {
+        match *self {
+            Expr::Call { span, .. } => span,
+            Expr::Set { span, .. } => span,
+            Expr::Let { span, .. } => span,
+            Expr::If { span, .. } => span,
+            Expr::Lambda { span, .. } => span,
+            Expr::Try { span, .. } => span,
+            Expr::Do(_, span) => span,
+            Expr::List(_, span) => span,
+            Expr::Literal(_, span) => span,
+            Expr::Symbol(_, span) => span
+        }
+    }
+
+    // Roughly how many bytes the expression takes up, counting what it owns.
+    pub fn size(&self) -> usize {
+        let symbols = |names: &Vec<Symbol>| names.len() * mem::size_of::<Symbol>();
+        mem::size_of::<Expr>() + match *self {
+            Expr::Call { ref head, ref args, .. } => head.size() + args.iter().map(Expr::size).sum::<usize>(),
+            Expr::Set { ref params, ref body, .. } => symbols(params) + body.size(),
+            Expr::Let { ref body, .. } => body.size(),
+            Expr::If { ref cond, ref then, ref otherwise, .. } => cond.size() + then.size() + otherwise.size(),
+            Expr::Lambda { ref params, ref body, .. } => symbols(params) + body.size(),
+            Expr::Try { ref body, ref catch, ref finally, .. } => {
+                body.size() + catch.as_ref().map_or(0, |catch| catch.1.size()) + finally.as_ref().map_or(0, |finally| finally.size())
+            },
+            Expr::Do(ref forms, _) => forms.iter().map(Expr::size).sum(),
+            Expr::List(ref items, _) => items.iter().map(Expr::size).sum(),
+            Expr::Literal(ref val, _) => val.size() - mem::size_of::<Value>(),
+            Expr::Symbol(..) => 0
+        }
+    }
+
+    // The name a call is made by, if it is not through a computed head.
+    pub fn call_name(&self) -> Option<Symbol> {
+        match *self {
+            Expr::Call { ref head, .. } => {
+                match **head {
+                    Expr::Symbol(name, _) => Some(name),
+                    _ => None
+                }
+            },
+            _ => None
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct LowerError {
+    pub message: String,
+    pub span: Span
+}
+
+impl LowerError {
+    pub fn new(message: String, span: Span) -> Self {
+        LowerError {
+            message: message,
+            span: span
+        }
+    }
+}
+
+impl fmt::Display for LowerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl Error for LowerError {}
+
+// How deeply a lowered program may nest. Lowering, compiling and dropping
+// an Expr recurse once per level, so deeper input would overflow the stack
+// instead of failing.
+pub const MAX_DEPTH: usize = 256;
+
+// Lowers each top level form of a parsed program.
+pub fn lower(ast: &Ast) -> Result<Vec<Expr>, LowerError> {
+    check_depth(ast)?;
+    match ast.node_val {
+        Some(_) => Ok(vec![lower_node(ast)?]),
+        None => ast.iter().map(lower_node).collect()
+    }
+}
+
+pub fn lower_expr(ast: &Ast) -> Result<Expr, LowerError> {
+    check_depth(ast)?;
+    lower_node(ast)
+}
+
+// Fails on the first form nested past MAX_DEPTH, counting the levels the
+// Expr will have. A when or unless wraps its forms in a do, and each cond
+// clause is an if inside the one before.
+fn check_depth(ast: &Ast) -> Result<(), LowerError> {
+    let mut stack = vec![(ast, 0)];
+    while let Some((node, depth)) = stack.pop() {
+        if depth > MAX_DEPTH {
+            return Err(LowerError::new(format!("forms are nested more than {} deep", MAX_DEPTH), child_span(node)));
+        }
+        let head = part_head(node);
+        let step = match head.as_deref() {
+            Some("when") | Some("unless") => 2,
+            _ => 1
+        };
+        let is_cond = head.as_deref() == Some("cond");
+        for (index, child) in node.iter().enumerate() {
+            stack.push((child, depth + step + match is_cond {
+                true => index,
+                false => 0
+            }));
+        }
+    }
+    Ok(())
+}
+
+fn lower_node(ast: &Ast) -> Result<Expr, LowerError> {
+    let tok = match ast.node_val {
+        Some(ref tok) => tok,
+        None => return Err(LowerError::new("expected an expression".to_string(), Span::default()))
+    };
+    let span = tok.span;
+    match tok.tok_type {
+        Type::Number => lower_number(tok.get_lexed(), span),
+        Type::StrType => Ok(Expr::Literal(Value::Str(tok.get_lexed()), span)),
+        Type::Func => Ok(Expr::Symbol(tok.symbol(), span)),
+        Type::OpenList => {
+            Ok(Expr::List(lower_all(ast.children())?, span))
+        },
+        Type::Oparen => lower_form(ast, span),
+        _ => Err(LowerError::new(format!("unexpected `{}`", tok.get_lexed()), span))
+    }
+}
+
+fn lower_number(lexed: String, span: Span) -> Result<Expr, LowerError> {
+    match token::parse_int(&lexed) {
+        Some(int) => Ok(Expr::Literal(Value::Int(int), span)),
+        None => match token::parse_float(&lexed) {
+            Some(float) => Ok(Expr::Literal(Value::Float(float), span)),
+            None => Err(LowerError::new(format!("invalid number `{}`", lexed), span))
+        }
+    }
+}
+
+fn lower_form(ast: &Ast, span: Span) -> Result<Expr, LowerError> {
+    let (head, children) = match ast.children().split_first() {
+        Some(split) => split,
+        None => return Err(LowerError::new("empty form".to_string(), span))
+    };
+    let head_tok = match head.node_val {
+        Some(ref tok) => tok,
+        None => return Err(LowerError::new("expected a name or a form to call".to_string(), span))
+    };
+    match head_tok.tok_type {
+        Type::Func => (),
+        Type::Oparen => {
+            return Ok(Expr::Call {
+                head: Box::new(lower_node(head)?),
+                args: lower_all(children)?,
+                span: span
+            });
+        },
+        _ => return Err(LowerError::new(format!("`{}` can not be called", head_tok.to_source()), head_tok.span))
+    }
+
+    let head_name = head_tok.symbol();
+    match head_name.as_str() {
+        "set" | "let" | "lambda" => lower_definition(head_name, children, span),
+        "if" => lower_if(children, span),
+        "cond" => lower_cond(children, span),
+        "when" | "unless" => lower_when(head_name, children, span),
+        "do" => Ok(Expr::Do(lower_all(children)?, span)),
+        "try" => lower_try(children, span),
+        "name" | "params" | "body" => {
+            let message = format!("`{}` is only valid inside `set`, `let` or `lambda`", head_tok.get_lexed());
+            Err(LowerError::new(message, head_tok.span))
+        },
+        "catch" | "finally" => {
+            let message = format!("`{}` is only valid inside `try`", head_tok.get_lexed());
+            Err(LowerError::new(message, head_tok.span))
+        },
+        "else" => Err(LowerError::new("`else` is only valid inside `cond`".to_string(), head_tok.span)),
+        _ => Ok(Expr::Call {
+            head: Box::new(Expr::Symbol(head_name, head_tok.span)),
+            args: lower_all(children)?,
+            span: span
+        })
+    }
+}
+
+// Each form has its own function, so lowering a deeply nested call only
+// pays for the frames on its path.
+fn lower_definition(form: Symbol, children: &[Ast], span: Span) -> Result<Expr, LowerError> {
+    let mut parts = Parts::new(form.as_str(), children, span)?;
+    match form.as_str() {
+        "set" => Ok(Expr::Set {
+            name: parts.name()?,
+            params: parts.params()?,
+            body: Box::new(parts.body()?),
+            span: span
+        }),
+        "let" => Ok(Expr::Let {
+            name: parts.name()?,
+            body: Box::new(parts.body()?),
+            span: span
+        }),
+        _ => Ok(Expr::Lambda {
+            params: parts.params()?,
+            body: Box::new(parts.body()?),
+            span: span
+        })
+    }
+}
+
+fn lower_if(children: &[Ast], span: Span) -> Result<Expr, LowerError> {
+    if children.len() != 2 && children.len() != 3 {
+        let message = format!("`if` expects a condition and one or two branches, found {} form(s)", children.len());
+        return Err(LowerError::new(message, span));
+    }
+    let cond = lower_node(&children[0])?;
+    let then = lower_node(&children[1])?;
+    // Without an else branch the value is nil when the condition is false.
+    let otherwise = match children.get(2) {
+        Some(otherwise) => lower_node(otherwise)?,
+        None => Expr::Literal(Value::Nil, span)
+    };
+    Ok(Expr::If {
+        cond: Box::new(cond),
+        then: Box::new(then),
+        otherwise: Box::new(otherwise),
+        form: Symbol::intern("if"),
+        span: span
+    })
+}
+
+fn lower_when(form: Symbol, children: &[Ast], span: Span) -> Result<Expr, LowerError> {
+    if children.len() < 2 {
+        let message = format!("`{}` expects a condition and at least one form", form);
+        return Err(LowerError::new(message, span));
+    }
+    let cond = lower_node(&children[0])?;
+    let forms = sequence(lower_all(&children[1..])?, span);
+    let nil = Expr::Literal(Value::Nil, span);
+    let (then, otherwise) = match form.as_str() == "when" {
+        true => (forms, nil),
+        false => (nil, forms)
+    };
+    Ok(Expr::If {
+        cond: Box::new(cond),
+        then: Box::new(then),
+        otherwise: Box::new(otherwise),
+        form: form,
+        span: span
+    })
+}
+
+// (cond (test form ...) ... (else form ...)), as nested ifs. The first
+// clause whose test is true has its forms evaluated, and without an else
+// the value is nil when none is.
+fn lower_cond(children: &[Ast], span: Span) -> Result<Expr, LowerError> {
+    let mut clauses = Vec::new();
+    let mut otherwise = None;
+    for child in children.iter() {
+        let clause_span = child_span(child);
+        if otherwise.is_some() {
+            return Err(LowerError::new("`else` must be the last clause of `cond`".to_string(), clause_span));
+        }
+        let parts = match child.node_val {
+            Some(ref tok) if tok.tok_type == Type::Oparen && child.child_count() >= 2 => child.children(),
+            _ => return Err(LowerError::new("expected `(test form ...)` in `cond`".to_string(), clause_span))
+        };
+        let forms = sequence(lower_all(&parts[1..])?, clause_span);
+        match part_head(child) {
+            Some(ref head) if head == "else" => otherwise = Some(forms),
+            _ => clauses.push((lower_node(&parts[0])?, forms, clause_span))
+        }
+    }
+    let mut lowered = otherwise.unwrap_or(Expr::Literal(Value::Nil, span));
+    for (cond, then, clause_span) in clauses.into_iter().rev() {
+        lowered = Expr::If {
+            cond: Box::new(cond),
+            then: Box::new(then),
+            otherwise: Box::new(lowered),
+            form: Symbol::intern("cond"),
+            span: clause_span
+        };
+    }
+    Ok(lowered)
+}
+
+// The forms as one expression, evaluated in order.
+fn sequence(mut forms: Vec<Expr>, span: Span) -> Expr {
+    match forms.len() {
+        1 => forms.pop().unwrap(),
+        _ => Expr::Do(forms, span)
+    }
+}
+
+// (try form (catch (params e) (body handler)) (finally cleanup)), where
+// either the catch or the finally may be left out.
+fn lower_try(children: &[Ast], span: Span) -> Result<Expr, LowerError> {
+    let (body, rest) = match children.split_first() {
+        Some(split) => split,
+        None => return Err(LowerError::new("`try` expects a form to try".to_string(), span))
+    };
+    let mut catch = None;
+    let mut finally = None;
+    for child in rest.iter() {
+        match part_head(child) {
+            Some(ref head) if head == "catch" && catch.is_none() => {
+                let mut parts = Parts::new("catch", &child.children()[1..], child_span(child))?;
+                let params = parts.params()?;
+                if params.len() != 1 {
+                    return Err(LowerError::new("`catch` expects exactly one param".to_string(), child_span(child)));
+                }
+                catch = Some((params[0], Box::new(parts.body()?)));
+            },
+            Some(ref head) if head == "finally" && finally.is_none() => {
+                match child.children().len() {
+                    2 => finally = Some(Box::new(lower_node(&child.children()[1])?)),
+                    _ => return Err(LowerError::new("`finally` expects exactly one form".to_string(), child_span(child)))
+                }
+            },
+            _ => return Err(LowerError::new("expected `(catch ...)` or `(finally ...)` in `try`".to_string(), child_span(child)))
+        }
+    }
+    if catch.is_none() && finally.is_none() {
+        return Err(LowerError::new("`try` needs a `(catch ...)` or a `(finally ...)`".to_string(), span));
+    }
+    Ok(Expr::Try {
+        body: Box::new(lower_node(body)?),
+        catch: catch,
+        finally: finally,
+        span: span
+    })
+}
+
+fn lower_all(children: &[Ast]) -> Result<Vec<Expr>, LowerError> {
+    let mut exprs = Vec::with_capacity(children.len());
+    for child in children.iter() {
+        exprs.push(lower_node(child)?);
+    }
+    Ok(exprs)
+}
+
+// The (name ...), (params ...) and (body ...) parts of a definition, found by
+// their head so they may come in any order.
+struct Parts<'a> {
+    form: &'static str,
+    span: Span,
+    parts: Vec<(String, &'a Ast)>
+}
+
+impl<'a> Parts<'a> {
+    fn new(form: &'static str, children: &'a [Ast], span: Span) -> Result<Self, LowerError> {
+        let mut parts: Vec<(String, &'a Ast)> = Vec::new();
+        for child in children.iter() {
+            let head = part_head(child);
+            match head {
+                Some(ref head) if head == "name" || head == "params" || head == "body" => {
+                    if parts.iter().any(|part| part.0 == *head) {
+                        return Err(LowerError::new(format!("`{}` has more than one `({} ...)`", form, head), child_span(child)));
+                    }
+                },
+                _ => return Err(LowerError::new(format!("unexpected form in `{}`", form), child_span(child)))
+            }
+            parts.push((head.unwrap(), child));
+        }
+        Ok(Parts {
+            form: form,
+            span: span,
+            parts: parts
+        })
+    }
+
+    fn take(&mut self, part: &str) -> Result<&'a [Ast], LowerError> {
+        match self.parts.iter().position(|found| found.0 == part) {
+            Some(index) => Ok(&self.parts.remove(index).1.children()[1..]),
+            None => Err(LowerError::new(format!("`{}` is missing its `({} ...)` form", self.form, part), self.span))
+        }
+    }
+
+    fn name(&mut self) -> Result<Symbol, LowerError> {
+        let span = self.span;
+        let names = self.take("name")?;
+        match names.len() {
+            1 => symbol_name(&names[0]),
+            _ => Err(LowerError::new("`name` expects exactly one name".to_string(), span))
+        }
+    }
+
+    fn params(&mut self) -> Result<Vec<Symbol>, LowerError> {
+        self.take("params")?.iter().map(symbol_name).collect()
+    }
+
+    // More than one form is evaluated in order, as in a do.
+    fn body(&mut self) -> Result<Expr, LowerError> {
+        let span = self.span;
+        let forms = self.take("body")?;
+        match forms.len() {
+            0 => Err(LowerError::new("`body` expects at least one form".to_string(), span)),
+            _ => Ok(sequence(lower_all(forms)?, span))
+        }
+    }
+}
+
+fn part_head(ast: &Ast) -> Option<String> {
+    match ast.node_val {
+        Some(ref tok) if tok.tok_type == Type::Oparen => {
+            match ast.child(0).and_then(|head| head.node_val.as_ref()) {
+                Some(head) if head.tok_type == Type::Func => Some(head.get_lexed()),
+                _ => None
+            }
+        },
+        _ => None
+    }
+}
+
+fn child_span(ast: &Ast) -> Span {
+    ast.node_val.as_ref().map_or(Span::default(), |tok: &Token| tok.span)
+}
+
+fn symbol_name(ast: &Ast) -> Result<Symbol, LowerError> {
+    match ast.node_val {
+        Some(ref tok) if tok.tok_type == Type::Func => Ok(tok.symbol()),
+        Some(ref tok) => Err(LowerError::new(format!("expected a name, found `{}`", tok.to_source()), tok.span)),
+        None => Err(LowerError::new("expected a name".to_string(), Span::default()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use build::{self, FormBuilder};
+    use eval::RuntimeErrorKind;
+    use lex::Lexer;
+    use parse::{Parser, ParserTrait};
+    use vm::Vm;
+    use {Error, Interpreter};
+
+    fn nested(head: &str, depth: usize) -> String {
+        format!("{}1{}", head.repeat(depth), ")".repeat(depth))
+    }
+
+    // The same as nested, built directly.
+    fn nested_tree(head: &str, depth: usize) -> Ast {
+        let mut ast = build::IntoAst::into_ast(1);
+        for _ in 0..depth {
+            ast = match head {
+                "when" => FormBuilder::call("when").arg(true).arg(ast).build(),
+                _ => FormBuilder::call(head).arg(1).arg(ast).build()
+            };
+        }
+        build::program(vec![ast])
+    }
+
+    fn parse(src: &str) -> Ast {
+        let mut parser = Parser::new();
+        for tok in Lexer::new(src) {
+            parser.parse_token(tok.unwrap()).unwrap();
+        }
+        parser.finish().unwrap()
+    }
+
+    fn malformed(result: Result<Value, Error>) -> bool {
+        match result {
+            Err(Error::Runtime(ref err)) => err.kind == RuntimeErrorKind::MalformedForm,
+            _ => false
+        }
+    }
+
+    #[test]
+    fn nesting_up_to_the_limit_runs() {
+        let src = nested("(add 1 ", MAX_DEPTH - 1);
+        assert_eq!(Interpreter::new().eval_str(&src).unwrap(), Value::Int(MAX_DEPTH as i64));
+        assert_eq!(Vm::new().run(&parse(&src)).unwrap(), Value::Int(MAX_DEPTH as i64));
+        let src = nested("(when true ", MAX_DEPTH / 2 - 1);
+        assert_eq!(Interpreter::new().eval_str(&src).unwrap(), Value::Int(1));
+    }
+
+    #[test]
+    fn deeper_nesting_is_malformed() {
+        let src = nested("(add 1 ", 5000);
+        let err = lower(&parse(&src)).unwrap_err();
+        assert_eq!(err.message, format!("forms are nested more than {} deep", MAX_DEPTH));
+        assert!(malformed(Interpreter::new().eval_str(&src)));
+        assert_eq!(Vm::new().run(&parse(&src)).unwrap_err().kind, RuntimeErrorKind::MalformedForm);
+        assert!(lower(&nested_tree("add", MAX_DEPTH)).is_err());
+        assert!(lower(&nested_tree("when", MAX_DEPTH / 2 + 1)).is_err());
+        assert!(nested_tree("add", 5000) == parse(&src));
+    }
+
+    #[test]
+    fn lowers_built_forms() {
+        // Built trees have no spans.
+        let span = Span::default();
+        let call = |name: &str, args: Vec<Expr>| Expr::Call {
+            head: Box::new(Expr::Symbol(Symbol::intern(name), span)),
+            args: args,
+            span: span
+        };
+        let double = hackit!((set (name double) (params x) (body (mult (x) 2))));
+        assert_eq!(lower(&double).unwrap(), vec![Expr::Set {
+            name: Symbol::intern("double"),
+            params: vec![Symbol::intern("x")],
+            body: Box::new(call("mult", vec![call("x", Vec::new()), Expr::Literal(Value::Int(2), span)])),
+            span: span
+        }]);
+        let items = vec![Value::Int(1), Value::Float(-2.5), Value::Str("x".to_string())];
+        assert_eq!(lower(&hackit!((println [1 -2.5 {"x"}]))).unwrap(), vec![
+            call("println", vec![Expr::List(items.into_iter().map(|item| Expr::Literal(item, span)).collect(), span)])
+        ]);
+    }
+
+    #[test]
+    fn long_conds_count_as_nesting() {
+        let clause = || hackit!((false 1));
+        let cond = FormBuilder::call("cond").args((0..MAX_DEPTH + 1).map(|_| clause())).arg(hackit!((else 2)));
+        assert!(lower(&cond.build()).is_err());
+        let clauses: String = (0..MAX_DEPTH / 2).map(|_| "(false 1) ").collect();
+        assert_eq!(Interpreter::new().eval_str(&format!("(cond {}(else 2))", clauses)).unwrap(), Value::Int(2));
+    }
+}
@@ -0,0 +1,191 @@
+use lex::Lexer;
+use parse::{Parser, ParserTrait};
+use token::{Token, TokenTrait, Type};
+use Error;
+
+// The formatter works on tokens rather than on Ast so comments survive.
+enum Node {
+    Atom(Token),
+    Comment { text: String, trailing: bool },
+    Form { open: Token, children: Vec<Node> }
+}
+
+// Re-emits src in the canonical style: one top level form per line with at
+// most one blank line between them, forms kept on one line when they fit in
+// width and otherwise broken one argument per line, indented by two.
+pub fn format_source(src: &str, width: usize) -> Result<String, Error> {
+    let tokens = Lexer::new(src).collect::<Result<Vec<Token>, _>>()?;
+    let mut parser = Parser::new();
+    for tok in tokens.iter() {
+        parser.parse_token(tok.clone())?;
+    }
+    parser.finish()?;
+
+    let mut formatted = String::new();
+    for (i, &(ref node, blank_before)) in build_tree(tokens).iter().enumerate() {
+        if i > 0 {
+            if is_trailing_comment(node) {
+                formatted.push(' ');
+            } else {
+                formatted.push('\n');
+                if blank_before {
+                    formatted.push('\n');
+                }
+            }
+        }
+        render(node, 0, width, &mut formatted);
+    }
+    if !formatted.is_empty() {
+        formatted.push('\n');
+    }
+    Ok(formatted)
+}
+
+pub fn is_formatted(src: &str, width: usize) -> Result<bool, Error> {
+    Ok(format_source(src, width)? == src)
+}
+
+// Pairs each top level node with whether a blank line came before it.
+fn build_tree(tokens: Vec<Token>) -> Vec<(Node, bool)> {
+    let mut top = Vec::new();
+    let mut stack: Vec<(Token, Vec<Node>, bool)> = Vec::new();
+    let mut newlines = 2;
+    for tok in tokens.into_iter() {
+        let blank_before = newlines > 1;
+        let node = match tok.tok_type {
+            Type::Space => {
+                if tok.get_lexed() == "\n" {
+                    newlines += 1;
+                }
+                continue;
+            },
+            Type::Oparen | Type::OpenList => {
+                stack.push((tok, Vec::new(), blank_before));
+                newlines = 0;
+                continue;
+            },
+            Type::Cparen | Type::CloseList => {
+                let (open, children, blank_before) = stack.pop().unwrap();
+                newlines = 0;
+                match stack.last_mut() {
+                    Some(parent) => parent.1.push(Node::Form { open: open, children: children }),
+                    None => top.push((Node::Form { open: open, children: children }, blank_before))
+                }
+                continue;
+            },
+            Type::Comment => Node::Comment {
+                text: tok.get_lexed().trim_end().to_string(),
+                trailing: newlines == 0
+            },
+            _ => Node::Atom(tok)
+        };
+        newlines = 0;
+        match stack.last_mut() {
+            Some(parent) => parent.1.push(node),
+            None => top.push((node, blank_before))
+        }
+    }
+    top
+}
+
+fn is_trailing_comment(node: &Node) -> bool {
+    match *node {
+        Node::Comment { trailing, .. } => trailing,
+        _ => false
+    }
+}
+
+fn has_comment(node: &Node) -> bool {
+    match *node {
+        Node::Atom(_) => false,
+        Node::Comment { .. } => true,
+        Node::Form { ref children, .. } => children.iter().any(has_comment)
+    }
+}
+
+fn flat(node: &Node, out: &mut String) {
+    match *node {
+        Node::Atom(ref tok) => out.push_str(&tok.to_source()),
+        Node::Comment { ref text, .. } => out.push_str(text),
+        Node::Form { ref open, ref children } => {
+            out.push_str(&open.to_source());
+            for (i, child) in children.iter().enumerate() {
+                if i > 0 {
+                    out.push(' ');
+                }
+                flat(child, out);
+            }
+            out.push_str(open.tok_type.closing_lexeme());
+        }
+    }
+}
+
+fn render(node: &Node, indent: usize, width: usize, out: &mut String) {
+    let (open, children) = match *node {
+        Node::Form { ref open, ref children } => (open, children),
+        _ => return flat(node, out)
+    };
+    if !has_comment(node) {
+        let mut line = String::new();
+        flat(node, &mut line);
+        if indent + line.len() <= width || children.len() < 2 {
+            out.push_str(&line);
+            return;
+        }
+    }
+    out.push_str(&open.to_source());
+    let child_indent = indent + 2;
+    for (i, child) in children.iter().enumerate() {
+        if i > 0 {
+            if is_trailing_comment(child) {
+                out.push(' ');
+            } else {
+                out.push('\n');
+                out.push_str(&" ".repeat(child_indent));
+            }
+        }
+        render(child, child_indent, width, out);
+    }
+    // A closer after a comment would be commented out.
+    if let Some(&Node::Comment { .. }) = children.last() {
+        out.push('\n');
+        out.push_str(&" ".repeat(indent));
+    }
+    out.push_str(open.tok_type.closing_lexeme());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ast::Ast;
+
+    const SOURCE: &str = "; fib, the slow way\n(set (name fib) (params n) ; n from 0\n  (body (if (eq (n) 0) 0\n    (if (eq (n) 1) 1 (add (fib (sub (n) 1)) (fib (sub (n) 2)))))))\n\n\n\
+                          (println \"a \\\"quoted\\\" \\\\ word\\n\" <1 -2.5e3 0x1f <> <\"é\" (f)>>)\n(fib 20) ; trailing\n";
+
+    fn parse(src: &str) -> Ast {
+        let mut parser = Parser::new();
+        for tok in Lexer::new(src) {
+            parser.parse_token(tok.unwrap()).unwrap();
+        }
+        parser.finish().unwrap()
+    }
+
+    #[test]
+    fn formatted_source_parses_back_to_the_same_tree() {
+        let ast = parse(SOURCE);
+        for &width in [0, 8, 20, 40, 80, 1000].iter() {
+            let formatted = format_source(SOURCE, width).unwrap();
+            assert!(parse(&formatted) == ast, "width {}:\n{}", width, formatted);
+            assert_eq!(format_source(&formatted, width).unwrap(), formatted, "width {}", width);
+            assert!(is_formatted(&formatted, width).unwrap());
+        }
+    }
+
+    #[test]
+    fn comments_survive_formatting() {
+        let formatted = format_source(SOURCE, 20).unwrap();
+        for comment in ["; fib, the slow way", "; n from 0", "; trailing"].iter() {
+            assert!(formatted.contains(comment), "{}", formatted);
+        }
+    }
+}
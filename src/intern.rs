@@ -0,0 +1,182 @@
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::{BuildHasherDefault, Hasher};
+use std::mem;
+use std::sync::{Mutex, OnceLock};
+
+// A name interned into a process wide table. Two symbols are equal exactly
+// when their names are, so scopes can compare and hash them without
+// touching the strings.
+//
+// Names are leaked so as_str can hand them out as &'static str. There is
+// one per distinct name, however many times it is interned, but none is
+// ever freed. So that untrusted scripts can not grow the process without
+// bound, the next evaluation on the thread that interned a name is charged
+// for it against Limits::max_memory, and its scope keeps the charge for
+// the evaluations after.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Symbol(u32);
+
+// Ids by name. Only interning takes the lock.
+fn ids() -> &'static Mutex<HashMap<&'static str, u32>> {
+    static IDS: OnceLock<Mutex<HashMap<&'static str, u32>>> = OnceLock::new();
+    IDS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+thread_local! {
+    // Bytes of names this thread interned that no evaluation has been
+    // charged for yet.
+    static UNCHARGED: Cell<usize> = const { Cell::new(0) };
+}
+
+// Takes the bytes of the names this thread has interned since it was last
+// called, each name counting its length and its entries in the tables.
+pub fn take_uncharged() -> usize {
+    UNCHARGED.with(|bytes| bytes.replace(0))
+}
+
+// Names by id, in segments that double in size so a name never moves once
+// written. Segment k holds ids 2^k - 1 up to 2^(k + 1) - 2. Reading a name
+// is a pair of atomic loads.
+const SEGMENTS: usize = 32;
+
+type Segment = Box<[OnceLock<&'static str>]>;
+
+static NAMES: [OnceLock<Segment>; SEGMENTS] = [const { OnceLock::new() }; SEGMENTS];
+
+fn slot(id: u32) -> (usize, usize) {
+    let n = id as u64 + 1;
+    let segment = 63 - n.leading_zeros() as usize;
+    (segment, (n - (1 << segment)) as usize)
+}
+
+impl Symbol {
+    pub fn intern(name: &str) -> Symbol {
+        let mut ids = ids().lock().unwrap();
+        if let Some(&id) = ids.get(name) {
+            return Symbol(id);
+        }
+        let name: &'static str = Box::leak(name.to_string().into_boxed_str());
+        let id = ids.len() as u32;
+        let (segment, index) = slot(id);
+        let names = NAMES[segment].get_or_init(|| (0..1usize << segment).map(|_| OnceLock::new()).collect());
+        let _ = names[index].set(name);
+        ids.insert(name, id);
+        let size = name.len() + mem::size_of::<(&str, u32)>() + mem::size_of::<OnceLock<&str>>();
+        UNCHARGED.with(|bytes| bytes.set(bytes.get() + size));
+        Symbol(id)
+    }
+
+    pub fn as_str(self) -> &'static str {
+        let (segment, index) = slot(self.0);
+        NAMES[segment].get().and_then(|names| names[index].get()).expect("symbols are only made by interning")
+    }
+
+    // Symbols are numbered from 0 in the order they were first interned, so
+    // the index can be used to key a Vec.
+    pub fn index(self) -> usize {
+        self.0 as usize
+    }
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl fmt::Debug for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self.as_str())
+    }
+}
+
+// Symbols are already small unique integers, so spreading the bits with one
+// multiply is all the hashing they need.
+#[derive(Default)]
+pub struct SymbolHasher(u64);
+
+impl Hasher for SymbolHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes.iter() {
+            self.0 = (self.0.rotate_left(8) ^ *byte as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+        }
+    }
+
+    fn write_u32(&mut self, int: u32) {
+        self.0 = (int as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+pub type SymbolMap<V> = HashMap<Symbol, V, BuildHasherDefault<SymbolHasher>>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::InterpreterConfig;
+    use eval::RuntimeErrorKind;
+    use limits::Limits;
+    use std::thread;
+    use {Error, Interpreter};
+
+    #[test]
+    fn slots_fill_each_segment_in_turn() {
+        assert_eq!(slot(0), (0, 0));
+        assert_eq!(slot(1), (1, 0));
+        assert_eq!(slot(2), (1, 1));
+        assert_eq!(slot(3), (2, 0));
+        assert_eq!(slot(6), (2, 3));
+        assert_eq!(slot(u32::MAX - 1), (31, (1 << 31) - 1));
+    }
+
+    #[test]
+    fn names_read_back_across_threads() {
+        let threads: Vec<_> = (0..8).map(|t| thread::spawn(move || {
+            (0..500).map(|i| {
+                let name = format!("intern_test_{}", (t * 131 + i) % 1000);
+                (Symbol::intern(&name), name)
+            }).collect::<Vec<_>>()
+        })).collect();
+        for handle in threads {
+            for (symbol, name) in handle.join().unwrap() {
+                assert_eq!(symbol.as_str(), name);
+                assert_eq!(Symbol::intern(&name), symbol);
+            }
+        }
+    }
+
+    // Mentions 20 long names made for the round, in a lambda that is never
+    // called so nothing is defined.
+    fn names(round: usize) -> String {
+        let calls: String = (0..20).map(|i| format!("(charge_test_{}_{}_{})", round, i, "x".repeat(100))).collect();
+        format!("(lambda (params) (body {}))", calls)
+    }
+
+    #[test]
+    fn new_names_count_against_the_memory_limit() {
+        let config = InterpreterConfig::new().limits(Limits::new().max_memory(32 * 1024));
+        let mut interpreter = Interpreter::with_config(config);
+        for _ in 0..50 {
+            interpreter.eval_str(&names(0)).unwrap();
+        }
+        let mut rounds = 1;
+        let err = loop {
+            match interpreter.eval_str(&names(rounds)) {
+                Ok(_) => rounds += 1,
+                Err(err) => break err
+            }
+            assert!(rounds < 50, "names were never charged");
+        };
+        match err {
+            Error::Runtime(ref err) => assert_eq!(err.kind, RuntimeErrorKind::MemoryLimitExceeded),
+            ref err => panic!("expected a memory error, found {}", err)
+        }
+        assert!(rounds > 1);
+        assert_eq!(take_uncharged(), 0);
+    }
+}
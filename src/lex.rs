@@ -1,52 +1,226 @@
-use token::{LexResult, Token, TokenTrait};
+use std::error::Error;
+use std::fmt;
+use std::io::{self, BufRead};
+use token::{LexResult, Span, Token, TokenTrait, Type};
 
 pub struct Lexer<'a> {
-    unlexed: &'a str,
+    unlexed: Option<&'a str>,
+    chars: Box<dyn Iterator<Item = io::Result<char>> + 'a>,
+    pending: Option<Token>,
+    offset: usize,
+    line: usize,
+    col: usize,
+    done: bool
 }
 
+#[derive(Clone, Debug, PartialEq)]
+pub enum LexErrorKind {
+    InvalidCharacter(char),
+    InvalidNumber(String),
+    UnterminatedString,
+    Io(String)
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct LexError {
+    pub kind: LexErrorKind,
+    pub span: Span
+}
+
+impl LexError {
+    pub fn new(kind: LexErrorKind, span: Span) -> Self {
+        LexError {
+            kind: kind,
+            span: span
+        }
+    }
+}
+
+impl fmt::Display for LexError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            LexErrorKind::InvalidCharacter(c) => write!(f, "invalid character `{}`", c.escape_default()),
+            LexErrorKind::InvalidNumber(ref num) => write!(f, "invalid number literal `{}`", num),
+            LexErrorKind::UnterminatedString => write!(f, "unterminated string literal"),
+            LexErrorKind::Io(ref err) => write!(f, "could not read source: {}", err)
+        }
+    }
+}
+
+impl Error for LexError {}
+
 pub trait LexerTrait<'a> {
-    fn get_unlexed(&self) -> &'a str;
-    fn lex(&self) -> Option<Vec<Token>>;
+    fn get_unlexed(&self) -> Option<&'a str>;
+    fn lex(self) -> Result<Vec<Token>, LexError> where Self: Sized;
 }
 
 impl<'a> LexerTrait<'a> for Lexer<'a> {
-    fn get_unlexed(&self) -> &'a str {
+    fn get_unlexed(&self) -> Option<&'a str> {
         self.unlexed
     }
 
-    fn lex(&self) -> Option<Vec<Token>> {
-        let mut toks = Vec::new();
-        for unlexed_char in self.unlexed.to_string().chars() {
-            match toks.pop() {
-                None => toks.push(Token::new(unlexed_char)),
-                Some(mut tok) => {
-                    if tok.get_status() {
-                        toks.push(tok);
-                        toks.push(Token::new(unlexed_char));
-                    } else {
-                        match tok.lex_char(unlexed_char) {
-                            LexResult::Pass => toks.push(tok),
-                            LexResult::Finish => toks.push(tok),
-                            LexResult::FinishNew => {
-                                toks.push(tok);
-                                toks.push(Token::new(unlexed_char));
-                            },
-                            LexResult::FinishDelete => toks.push(tok),
-                            LexResult::Continue => toks.push(tok),
-                            LexResult::Fail => return None
-                        }
+    fn lex(self) -> Result<Vec<Token>, LexError> {
+        self.collect()
+    }
+}
+
+impl<'a> Iterator for Lexer<'a> {
+    type Item = Result<Token, LexError>;
+
+    fn next(&mut self) -> Option<Result<Token, LexError>> {
+        if self.done {
+            return None;
+        }
+        loop {
+            // Parens, spaces and closed strings are known to be complete as
+            // soon as they are started, so hand them out without waiting.
+            if self.pending.as_ref().is_some_and(|tok| tok.get_status()) {
+                return self.pending.take().map(Ok);
+            }
+            let result = match self.chars.next() {
+                Some(Ok(unlexed_char)) => self.lex_char(unlexed_char),
+                Some(Err(err)) => {
+                    let span = Span::new(self.offset, self.offset, self.line, self.col);
+                    Err(LexError::new(LexErrorKind::Io(err.to_string()), span))
+                },
+                None => {
+                    self.done = true;
+                    match self.pending.take() {
+                        Some(tok) => finish_last(tok).map(Some),
+                        None => Ok(None)
                     }
                 }
+            };
+            match result {
+                Ok(Some(tok)) => return Some(Ok(tok)),
+                Ok(None) => {
+                    if self.done {
+                        return None;
+                    }
+                },
+                Err(err) => {
+                    self.done = true;
+                    return Some(Err(err));
+                }
             }
         }
-        Some(toks)
     }
 }
 
 impl<'a> Lexer<'a> {
     pub fn new(newunlexed: &'a str) -> Self {
+        Lexer::from_chars(Some(newunlexed), Box::new(newunlexed.chars().map(Ok)))
+    }
+
+    // Reads a line at a time, so piped input is tokenized as it arrives.
+    pub fn from_reader<R: BufRead + 'a>(reader: R) -> Self {
+        Lexer::from_chars(None, Box::new(ReadChars {
+            reader: reader,
+            line: String::new(),
+            pos: 0
+        }))
+    }
+
+    fn from_chars(unlexed: Option<&'a str>, chars: Box<dyn Iterator<Item = io::Result<char>> + 'a>) -> Self {
         Lexer {
-            unlexed: newunlexed
+            unlexed: unlexed,
+            chars: chars,
+            pending: None,
+            offset: 0,
+            line: 1,
+            col: 1,
+            done: false
         }
     }
+
+    // Feeds one char to the token being built, handing back a token once the
+    // char shows that it is complete.
+    fn lex_char(&mut self, unlexed_char: char) -> Result<Option<Token>, LexError> {
+        let end = self.offset + unlexed_char.len_utf8();
+        let span = Span::new(self.offset, end, self.line, self.col);
+        self.offset = end;
+        if unlexed_char == '\n' {
+            self.line += 1;
+            self.col = 1;
+        } else {
+            self.col += 1;
+        }
+
+        let mut finished = None;
+        match self.pending.take() {
+            None => self.pending = Some(start_token(unlexed_char, span)?),
+            Some(mut tok) => {
+                let tok_type = tok.tok_type;
+                match tok.lex_char(unlexed_char) {
+                    LexResult::FinishNew => {
+                        check_finished(&tok)?;
+                        finished = Some(tok);
+                        self.pending = Some(start_token(unlexed_char, span)?);
+                    },
+                    LexResult::Fail => {
+                        let span = Span::new(tok.span.start, end, tok.span.line, tok.span.col);
+                        return Err(LexError::new(match tok_type {
+                            Type::Number => LexErrorKind::InvalidNumber(tok.get_lexed() + &unlexed_char.to_string()),
+                            _ => LexErrorKind::InvalidCharacter(unlexed_char)
+                        }, span));
+                    },
+                    _ => {
+                        tok.span.end = end;
+                        self.pending = Some(tok);
+                    }
+                }
+            }
+        }
+        Ok(finished)
+    }
+}
+
+struct ReadChars<R: BufRead> {
+    reader: R,
+    line: String,
+    pos: usize
+}
+
+impl<R: BufRead> Iterator for ReadChars<R> {
+    type Item = io::Result<char>;
+
+    fn next(&mut self) -> Option<io::Result<char>> {
+        if self.pos >= self.line.len() {
+            self.line.clear();
+            self.pos = 0;
+            match self.reader.read_line(&mut self.line) {
+                Ok(0) => return None,
+                Ok(_) => (),
+                Err(err) => return Some(Err(err))
+            }
+        }
+        let next_char = self.line[self.pos..].chars().next().unwrap();
+        self.pos += next_char.len_utf8();
+        Some(Ok(next_char))
+    }
+}
+
+fn finish_last(mut tok: Token) -> Result<Token, LexError> {
+    if tok.tok_type == Type::StrType && !tok.get_status() {
+        return Err(LexError::new(LexErrorKind::UnterminatedString, tok.span));
+    }
+    tok.finish();
+    check_finished(&tok)?;
+    Ok(tok)
+}
+
+fn start_token(unlexed_char: char, span: Span) -> Result<Token, LexError> {
+    let mut tok = Token::new(unlexed_char);
+    tok.span = span;
+    match tok.tok_type {
+        Type::Error => Err(LexError::new(LexErrorKind::InvalidCharacter(unlexed_char), span)),
+        _ => Ok(tok)
+    }
+}
+
+fn check_finished(tok: &Token) -> Result<(), LexError> {
+    match tok.tok_type {
+        Type::Error => Err(LexError::new(LexErrorKind::InvalidNumber(tok.get_lexed()), tok.span)),
+        _ => Ok(())
+    }
 }
@@ -1,6 +1,185 @@
+// Style lints only. The crate writes out `field: field`, uses a plain match
+// where matches! would do and gives types a new() without a Default.
+#![allow(clippy::redundant_field_names, clippy::match_like_matches_macro, clippy::new_without_default)]
+
 pub mod lex;
+#[macro_use]
+pub mod build;
 pub mod parse;
 pub mod token;
 pub mod ast;
 pub mod eval;
 pub mod builtins;
+pub mod diagnostics;
+pub mod value;
+pub mod format;
+pub mod lint;
+pub mod expr;
+pub mod intern;
+pub mod limits;
+pub mod config;
+pub mod serialize;
+pub mod compile;
+pub mod vm;
+pub mod debug;
+pub mod trace;
+pub mod profile;
+
+use ast::Ast;
+use config::InterpreterConfig;
+use debug::Debugger;
+use eval::{Eval, RuntimeError, Scope};
+use lex::{LexError, Lexer};
+use limits::Limits;
+use parse::{ParseError, Parser, ParserTrait};
+use profile::Profiler;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::Path;
+use trace::SharedTracer;
+use value::Value;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Lex(LexError),
+    Parse(ParseError),
+    Runtime(RuntimeError)
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Io(ref err) => write!(f, "{}", err),
+            Error::Lex(ref err) => write!(f, "{}", err),
+            Error::Parse(ref err) => write!(f, "{}", err),
+            Error::Runtime(ref err) => write!(f, "{}", err)
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+impl From<LexError> for Error {
+    fn from(err: LexError) -> Self {
+        Error::Lex(err)
+    }
+}
+
+impl From<ParseError> for Error {
+    fn from(err: ParseError) -> Self {
+        Error::Parse(err)
+    }
+}
+
+impl From<RuntimeError> for Error {
+    fn from(err: RuntimeError) -> Self {
+        Error::Runtime(err)
+    }
+}
+
+// Lexes, parses and evaluates in one call. Functions defined by one call
+// stay defined for the next, while limits start afresh for each call.
+// Interpreter::new gives the pure profile, see InterpreterConfig.
+pub struct Interpreter {
+    scope: Option<Scope>,
+    config: InterpreterConfig,
+    tracer: Option<SharedTracer>,
+    profiler: Option<Profiler>
+}
+
+impl Interpreter {
+    pub fn new() -> Self {
+        Interpreter::with_config(InterpreterConfig::new())
+    }
+
+    pub fn with_config(config: InterpreterConfig) -> Self {
+        Interpreter {
+            scope: Some(Scope::new_root()),
+            config: config,
+            tracer: None,
+            profiler: None
+        }
+    }
+
+    pub fn config(&self) -> &InterpreterConfig {
+        &self.config
+    }
+
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.config.limits = limits;
+    }
+
+    // Traces every evaluation from now on, or stops tracing with None.
+    pub fn set_tracer(&mut self, tracer: Option<SharedTracer>) {
+        self.tracer = tracer;
+    }
+
+    // Profiles every evaluation from now on, adding to the same counts,
+    // until the profiler is taken back.
+    pub fn set_profiler(&mut self, profiler: Option<Profiler>) {
+        self.profiler = profiler;
+    }
+
+    pub fn take_profiler(&mut self) -> Option<Profiler> {
+        self.profiler.take()
+    }
+
+    pub fn eval_str(&mut self, src: &str) -> Result<Value, Error> {
+        self.eval_lexer(Lexer::new(src))
+    }
+
+    pub fn eval_file<P: AsRef<Path>>(&mut self, path: P) -> Result<Value, Error> {
+        let file = File::open(path)?;
+        self.eval_lexer(Lexer::from_reader(BufReader::new(file)))
+    }
+
+    // Sets up src to be run under a debugger, against a copy of the
+    // interpreter's definitions. The interpreter keeps its own until the
+    // debugger is handed to end_debug, so dropping the debugger instead
+    // leaves it as it was.
+    pub fn debug_str(&mut self, src: &str) -> Result<Debugger, Error> {
+        let tree = parse_lexer(Lexer::new(src))?;
+        let scope = self.scope.clone().unwrap_or_else(Scope::new_root);
+        Ok(Debugger::new(self.new_eval(tree), scope))
+    }
+
+    // Takes on the definitions as the debugger left them, replacing any
+    // made by other calls while it ran.
+    pub fn end_debug(&mut self, debugger: Debugger) {
+        self.scope = Some(debugger.into_scope());
+    }
+
+    fn eval_lexer(&mut self, lexer: Lexer) -> Result<Value, Error> {
+        let tree = parse_lexer(lexer)?;
+        let scope = self.scope.take().unwrap_or_else(Scope::new_root);
+        let mut eval = self.new_eval(tree);
+        eval.set_profiler(self.profiler.take());
+        let (scope, result) = eval.eval_with_scope(scope);
+        self.profiler = eval.take_profiler();
+        self.scope = Some(scope);
+        Ok(result?)
+    }
+
+    fn new_eval(&self, tree: Ast) -> Eval {
+        let mut eval = Eval::new(tree);
+        eval.set_config(&self.config);
+        eval.set_tracer(self.tracer.clone());
+        eval
+    }
+}
+
+fn parse_lexer(lexer: Lexer) -> Result<Ast, Error> {
+    let mut parser = Parser::new();
+    for tok in lexer {
+        parser.parse_token(tok?)?;
+    }
+    Ok(parser.finish()?)
+}
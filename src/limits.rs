@@ -0,0 +1,54 @@
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::time::Duration;
+
+// Bounds on one evaluation, for scripts that can not be trusted to stop on
+// their own. Everything is unbounded unless set.
+#[derive(Clone, Debug, Default)]
+pub struct Limits {
+    // Evaluation steps allowed. A builtin call costs one step more.
+    pub fuel: Option<u64>,
+    // User function calls that may be in progress at once.
+    pub max_depth: Option<usize>,
+    // Approximate bytes of values, pending work and definitions that may be
+    // live at once, definitions already in the scope included. Names the
+    // scope's programs interned count too, as they are never freed.
+    pub max_memory: Option<usize>,
+    // Wall clock time allowed, counted from when the limits are handed to
+    // Eval.
+    pub timeout: Option<Duration>,
+    // Evaluation stops soon after another thread sets this. It is left set,
+    // so clear it before reusing it.
+    pub cancel: Option<Arc<AtomicBool>>
+}
+
+impl Limits {
+    pub fn new() -> Self {
+        Limits::default()
+    }
+
+    pub fn fuel(mut self, fuel: u64) -> Self {
+        self.fuel = Some(fuel);
+        self
+    }
+
+    pub fn max_depth(mut self, depth: usize) -> Self {
+        self.max_depth = Some(depth);
+        self
+    }
+
+    pub fn max_memory(mut self, bytes: usize) -> Self {
+        self.max_memory = Some(bytes);
+        self
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub fn cancel_token(mut self, cancel: Arc<AtomicBool>) -> Self {
+        self.cancel = Some(cancel);
+        self
+    }
+}
@@ -0,0 +1,290 @@
+use ast::{Ast, AstTrait, Visitor};
+use builtins;
+use config::InterpreterConfig;
+use std::fmt;
+use token::{Span, TokenTrait, Type};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum LintKind {
+    UndefinedName(String),
+    ArityMismatch { name: String, expected: usize, found: usize },
+    UnusedLet(String),
+    ShadowedBuiltin(String),
+    MalformedSet(&'static str),
+    MalformedLet(&'static str)
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Lint {
+    pub kind: LintKind,
+    pub span: Span
+}
+
+impl Lint {
+    pub fn new(kind: LintKind, span: Span) -> Self {
+        Lint {
+            kind: kind,
+            span: span
+        }
+    }
+
+    // Undefined names, bad arity and malformed definitions fail at runtime,
+    // the rest are only suspicious.
+    pub fn is_error(&self) -> bool {
+        match self.kind {
+            LintKind::UnusedLet(_) | LintKind::ShadowedBuiltin(_) => false,
+            _ => true
+        }
+    }
+}
+
+impl fmt::Display for Lint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            LintKind::UndefinedName(ref name) => write!(f, "undefined function `{}`", name),
+            LintKind::ArityMismatch { ref name, expected, found } => {
+                write!(f, "`{}` expects {} argument(s), found {}", name, expected, found)
+            },
+            LintKind::UnusedLet(ref name) => write!(f, "`let` binding `{}` is never used", name),
+            LintKind::ShadowedBuiltin(ref name) => write!(f, "definition of `{}` shadows a builtin", name),
+            LintKind::MalformedSet(part) => write!(f, "`set` is missing its `({} ...)` form", part),
+            LintKind::MalformedLet(part) => write!(f, "`let` is missing its `({} ...)` form", part)
+        }
+    }
+}
+
+struct Definition {
+    name: String,
+    // None for a let whose value may be a lambda, which is not known until
+    // it runs.
+    arity: Option<usize>,
+    span: Span,
+    is_let: bool,
+    used: bool
+}
+
+struct Linter<'a> {
+    config: &'a InterpreterConfig,
+    defs: Vec<Definition>,
+    locals: Vec<Vec<String>>,
+    lints: Vec<Lint>
+}
+
+// Checks a parsed program without running it, assuming every builtin is
+// available. A call refers to the set or let before it of that name, or
+// failing a builtin, to one after it.
+pub fn lint(ast: &Ast) -> Vec<Lint> {
+    lint_with_config(ast, &InterpreterConfig::trusted())
+}
+
+// The same, reporting builtins and host functions the config does not allow
+// as undefined.
+pub fn lint_with_config(ast: &Ast, config: &InterpreterConfig) -> Vec<Lint> {
+    let mut linter = Linter {
+        config: config,
+        defs: Vec::new(),
+        locals: Vec::new(),
+        lints: Vec::new()
+    };
+    ast.walk(&mut linter);
+    linter.check(ast);
+    for def in linter.defs.iter() {
+        if def.is_let && !def.used {
+            linter.lints.push(Lint::new(LintKind::UnusedLet(def.name.clone()), def.span));
+        }
+    }
+    linter.lints.sort_by_key(|lint| lint.span.start);
+    linter.lints
+}
+
+// The first pass, which records every definition so calls can be checked
+// against definitions that come later in the program.
+impl<'a> Visitor for Linter<'a> {
+    fn enter(&mut self, ast: &Ast) -> bool {
+        let is_let = match head_name(ast) {
+            Some(ref head) if head == "set" || head == "let" => head == "let",
+            _ => return true
+        };
+        let children = ast.children();
+        let span = head_span(ast);
+        let name = find_part(children, "name").and_then(|part| part.child(1)).and_then(leaf_name);
+        let params = find_part(children, "params");
+        let parts = match is_let {
+            true => &["name", "body"][..],
+            false => &["name", "params", "body"][..]
+        };
+        for part in parts.iter() {
+            let missing = match *part {
+                "name" => name.is_none(),
+                "params" => params.is_none(),
+                _ => find_part(children, part).is_none()
+            };
+            if missing {
+                self.lints.push(Lint::new(match is_let {
+                    true => LintKind::MalformedLet(part),
+                    false => LintKind::MalformedSet(part)
+                }, span));
+            }
+        }
+        if let Some(name) = name {
+            if builtins::is_builtin(&name) {
+                self.lints.push(Lint::new(LintKind::ShadowedBuiltin(name.clone()), span));
+            }
+            self.defs.push(Definition {
+                name: name,
+                arity: match is_let {
+                    true => find_part(children, "body").filter(|body| is_literal_body(body)).map(|_| 0),
+                    false => Some(params.map_or(0, |part| part.child_count() - 1))
+                },
+                span: span,
+                is_let: is_let,
+                used: false
+            });
+        }
+        true
+    }
+}
+
+impl<'a> Linter<'a> {
+    fn check(&mut self, ast: &Ast) {
+        let children = ast.children();
+        let head = match head_name(ast) {
+            Some(head) => head,
+            None => {
+                for child in children {
+                    self.check(child);
+                }
+                return;
+            }
+        };
+        match head.as_str() {
+            "set" | "lambda" | "catch" => {
+                let params = find_part(children, "params").map_or(Vec::new(), |part| {
+                    part.iter().skip(1).filter_map(leaf_name).collect()
+                });
+                self.locals.push(params);
+                if let Some(body) = find_part(children, "body") {
+                    self.check_args(body);
+                }
+                self.locals.pop();
+            },
+            "let" => {
+                if let Some(body) = find_part(children, "body") {
+                    self.check_args(body);
+                }
+            },
+            // A clause's head is its test, not a call.
+            "cond" => {
+                for clause in children.iter().skip(1) {
+                    for part in clause.iter() {
+                        self.check(part);
+                    }
+                }
+            },
+            "name" | "params" => (),
+            "body" => self.check_args(ast),
+            _ => {
+                let found = children.len() - 1;
+                if self.locals.iter().any(|locals| locals.contains(&head)) {
+                    // A param may hold a lambda, so calls to one are not
+                    // checked.
+                } else if let Some(def) = self.resolve(&head, head_span(ast)) {
+                    def.used = true;
+                    match def.arity {
+                        Some(expected) if expected != found => {
+                            let kind = LintKind::ArityMismatch { name: head.clone(), expected: expected, found: found };
+                            self.lints.push(Lint::new(kind, head_span(ast)));
+                        },
+                        _ => ()
+                    }
+                } else if !self.config.has_builtin(&head) && !builtins::SPECIAL_FORMS.contains(&head.as_str()) {
+                    self.lints.push(Lint::new(LintKind::UndefinedName(head.clone()), head_span(ast)));
+                }
+                self.check_args(ast);
+            }
+        }
+    }
+
+    // The definition a call made at span refers to. The last one made before
+    // the call comes first, then a builtin, then one made after, which a
+    // function body can call once it runs.
+    fn resolve(&mut self, name: &str, span: Span) -> Option<&mut Definition> {
+        let index = match self.defs.iter().rposition(|def| def.name == name && def.span.start < span.start) {
+            Some(index) => Some(index),
+            None if self.config.has_builtin(name) => None,
+            None => self.defs.iter().rposition(|def| def.name == name)
+        };
+        index.map(move |index| &mut self.defs[index])
+    }
+
+    fn check_args(&mut self, ast: &Ast) {
+        for child in ast.iter().skip(1) {
+            self.check(child);
+        }
+    }
+}
+
+// Whether a (body ...) holds just a number or a string.
+fn is_literal_body(body: &Ast) -> bool {
+    match body.children() {
+        [_, value] => value.node_val.as_ref().is_some_and(|tok| tok.tok_type == Type::Number || tok.tok_type == Type::StrType),
+        _ => false
+    }
+}
+
+fn leaf_name(ast: &Ast) -> Option<String> {
+    match ast.node_val {
+        Some(ref tok) if tok.tok_type == Type::Func => Some(tok.get_lexed()),
+        _ => None
+    }
+}
+
+fn head_name(ast: &Ast) -> Option<String> {
+    match ast.node_val {
+        Some(ref tok) if tok.tok_type == Type::Oparen => ast.child(0).and_then(leaf_name),
+        _ => None
+    }
+}
+
+fn head_span(ast: &Ast) -> Span {
+    match ast.child(0).and_then(|head| head.node_val.as_ref()) {
+        Some(tok) => tok.span,
+        None => ast.node_val.as_ref().map_or(Span::default(), |tok| tok.span)
+    }
+}
+
+fn find_part<'a>(children: &'a [Ast], part: &str) -> Option<&'a Ast> {
+    children.iter().find(|child| head_name(child).is_some_and(|head| head == part))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lex::Lexer;
+    use parse::{Parser, ParserTrait};
+
+    fn kinds(src: &str) -> Vec<LintKind> {
+        let mut parser = Parser::new();
+        for tok in Lexer::new(src) {
+            parser.parse_token(tok.unwrap()).unwrap();
+        }
+        lint(&parser.finish().unwrap()).into_iter().map(|lint| lint.kind).collect()
+    }
+
+    #[test]
+    fn builtin_calls_before_a_shadowing_set_are_checked_as_builtins() {
+        let src = "(add 1 2)\n(set (name add) (params x) (body (x)))\n(add 1 2)";
+        assert_eq!(kinds(src), vec![
+            LintKind::ShadowedBuiltin("add".to_string()),
+            LintKind::ArityMismatch { name: "add".to_string(), expected: 1, found: 2 }
+        ]);
+    }
+
+    #[test]
+    fn bodies_can_call_functions_set_later() {
+        let src = "(set (name f) (params) (body (g 1)))\n(set (name g) (params x) (body (x)))\n(f)";
+        assert_eq!(kinds(src), Vec::new());
+        let src = "(set (name f) (params) (body (g 1 2)))\n(set (name g) (params x) (body (x)))\n(f)";
+        assert_eq!(kinds(src), vec![LintKind::ArityMismatch { name: "g".to_string(), expected: 1, found: 2 }]);
+    }
+}
@@ -1,18 +1,76 @@
 use ast::{Ast, AstTrait};
-use token::{Token, Type};
+use std::error::Error;
+use std::fmt;
+use token::{Span, Token, TokenTrait, Type};
 
 pub struct Parser {
     stack: Vec<Ast>,
+    error: Option<ParseError>,
+    recovering: bool,
+    errors: Vec<ParseError>,
+    skip_depth: usize,
 }
 
+#[derive(Clone, Debug, PartialEq)]
+pub enum ParseErrorKind {
+    UnexpectedClose(String),
+    MismatchedClose { found: String, expected: String, open: Span },
+    Unclosed(String),
+    LiteralOutsideForm(String),
+    InvalidToken(String)
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParseError {
+    pub kind: ParseErrorKind,
+    pub span: Span
+}
+
+impl ParseError {
+    pub fn new(kind: ParseErrorKind, span: Span) -> Self {
+        ParseError {
+            kind: kind,
+            span: span
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            ParseErrorKind::UnexpectedClose(ref close) => write!(f, "unexpected `{}`", close),
+            ParseErrorKind::MismatchedClose { ref found, ref expected, .. } => {
+                write!(f, "mismatched `{}`, expected `{}`", found, expected)
+            },
+            ParseErrorKind::Unclosed(ref open) => write!(f, "unclosed `{}`", open),
+            ParseErrorKind::LiteralOutsideForm(ref lit) => write!(f, "`{}` is not inside a form", lit),
+            ParseErrorKind::InvalidToken(ref tok) => write!(f, "invalid token `{}`", tok)
+        }
+    }
+}
+
+impl Error for ParseError {}
+
 pub trait ParserTrait {
-    fn parse_token(&mut self, token: Token) -> bool;
+    fn parse_token(&mut self, token: Token) -> Result<(), ParseError>;
     fn is_done(&self) -> bool;
     fn get_parsed_tree(&mut self) -> Option<Ast>;
+    fn finish(&mut self) -> Result<Ast, ParseError>;
+    fn finish_recovering(&mut self) -> (Ast, Vec<ParseError>);
 }
 
 impl ParserTrait for Parser {
-    fn parse_token(&mut self, token: Token) -> bool {
+    fn parse_token(&mut self, token: Token) -> Result<(), ParseError> {
+        if let Some(ref err) = self.error {
+            return Err(err.clone());
+        }
+        if self.skip_depth > 0 && self.starts_top_level(&token) {
+            self.skip_depth = 0;
+        }
+        if self.skip_depth > 0 {
+            self.skip(&token);
+            return Ok(());
+        }
         let result = match token.tok_type {
             Type::Oparen => self.open(token),
             Type::Cparen => self.close(token),
@@ -21,12 +79,18 @@ impl ParserTrait for Parser {
             Type::CloseList => self.close(token),
             Type::Number => self.parse_literal(token),
             Type::StrType => self.parse_literal(token),
-            Type::Space => true,
-            Type::Empty => false,
-            Type::Error => false
+            Type::Comment => Ok(()),
+            Type::Space => Ok(()),
+            Type::Empty => Err(invalid_token(token)),
+            Type::Error => Err(invalid_token(token))
         };
-        if !result {
-            self.stack.clear();
+        if let Err(ref err) = result {
+            if self.recovering {
+                self.resync(err);
+            } else {
+                self.stack.clear();
+                self.error = Some(err.clone());
+            }
         }
         result
     }
@@ -41,53 +105,184 @@ impl ParserTrait for Parser {
             false => None
         }
     }
+
+    fn finish(&mut self) -> Result<Ast, ParseError> {
+        if let Some(ref err) = self.error {
+            return Err(err.clone());
+        }
+        match self.stack.pop() {
+            Some(tree) => {
+                match tree.node_val {
+                    Some(ref open) if !self.stack.is_empty() => {
+                        let err = ParseError::new(ParseErrorKind::Unclosed(open.get_lexed()), open.span);
+                        self.stack.clear();
+                        self.error = Some(err.clone());
+                        Err(err)
+                    },
+                    _ => Ok(tree)
+                }
+            },
+            None => Ok(Ast::new_null())
+        }
+    }
+
+    fn finish_recovering(&mut self) -> (Ast, Vec<ParseError>) {
+        self.close_unfinished();
+        self.skip_depth = 0;
+        let tree = self.stack.pop().unwrap_or_else(Ast::new_null);
+        self.stack.push(Ast::new_null());
+        (tree, self.errors.drain(..).collect())
+    }
 }
 
 impl Parser {
     pub fn new() -> Self {
         Parser {
             stack: vec![Ast::new_null()],
+            error: None,
+            recovering: false,
+            errors: Vec::new(),
+            skip_depth: 0,
+        }
+    }
+
+    // A parser that keeps going after an error by dropping the top level form
+    // it happened in. Use finish_recovering to collect every error along with
+    // the forms that did parse.
+    pub fn new_recovering() -> Self {
+        let mut parser = Parser::new();
+        parser.recovering = true;
+        parser
+    }
+
+    // While skipping after an error, an open paren in the first column is
+    // taken as the start of a new top level form, so one bad form does not
+    // swallow the rest of the file. A form that is still open can have
+    // nested forms in the first column, so those are parsed as usual.
+    fn starts_top_level(&self, token: &Token) -> bool {
+        match token.tok_type {
+            Type::Oparen | Type::OpenList => token.span.col == 1,
+            _ => false
         }
     }
 
-    fn open(&mut self, token: Token) -> bool {
+    fn skip(&mut self, token: &Token) {
+        match token.tok_type {
+            Type::Oparen | Type::OpenList => self.skip_depth += 1,
+            Type::Cparen | Type::CloseList => self.skip_depth -= 1,
+            _ => ()
+        }
+    }
+
+    fn close_unfinished(&mut self) {
+        if self.stack.len() > 1 {
+            let open = self.stack[1].node_val.clone().unwrap();
+            self.errors.push(ParseError::new(ParseErrorKind::Unclosed(open.get_lexed()), open.span));
+            self.stack.truncate(1);
+        }
+    }
+
+    // Skips the rest of the top level form the error happened in. A
+    // mismatched close is not counted, as it is as likely to be a stray as a
+    // typo for the right one.
+    fn resync(&mut self, err: &ParseError) {
+        self.skip_depth = self.stack.len() - 1;
+        self.stack.truncate(1);
+        self.errors.push(err.clone());
+    }
+
+    fn open(&mut self, token: Token) -> Result<(), ParseError> {
         self.stack.push(Ast::new(token));
-        true
+        Ok(())
     }
 
-    fn close(&mut self, token: Token) -> bool {
-        match self.stack.pop() {
-            Some(child) => {
-                match self.stack.pop() {
-                    Some(mut parent) => {
-                        let should_push = match child.node_val {
-                            Some(ref tok) => tok.tok_type.is_matching_close(token.tok_type),
-                            None => false
-                        };
-                        if should_push {
-                            parent.push_child(child);
-                            self.stack.push(parent);
-                        }
-                        should_push
-                    },
-                    None => true
-                }
-            },
-            None => false
+    fn close(&mut self, token: Token) -> Result<(), ParseError> {
+        if self.stack.len() < 2 {
+            return Err(ParseError::new(ParseErrorKind::UnexpectedClose(token.get_lexed()), token.span));
+        }
+        let mut child = self.stack.pop().unwrap();
+        let open = child.node_val.clone().unwrap();
+        if open.tok_type.is_matching_close(token.tok_type) {
+            if let Some(ref mut tok) = child.node_val {
+                tok.span = tok.span.to(token.span);
+            }
+            self.stack.last_mut().unwrap().push_child(child);
+            Ok(())
+        } else {
+            self.stack.push(child);
+            let kind = ParseErrorKind::MismatchedClose {
+                found: token.get_lexed(),
+                expected: open.tok_type.closing_lexeme().to_string(),
+                open: open.span
+            };
+            Err(ParseError::new(kind, token.span))
         }
     }
 
-    fn parse_literal(&mut self, token: Token) -> bool {
-        match self.stack.pop() {
-            Some(mut node) => {
-                let should_push = node.node_val.is_some();
-                if should_push {
-                    node.push_child(Ast::new(token));
-                    self.stack.push(node);
-                }
-                should_push
+    fn parse_literal(&mut self, mut token: Token) -> Result<(), ParseError> {
+        token.intern();
+        match self.stack.last_mut() {
+            Some(ref mut node) if node.node_val.is_some() => {
+                node.push_child(Ast::new(token));
+                Ok(())
             },
-            None => false
+            _ => Err(ParseError::new(ParseErrorKind::LiteralOutsideForm(token.get_lexed()), token.span))
+        }
+    }
+}
+
+fn invalid_token(token: Token) -> ParseError {
+    ParseError::new(ParseErrorKind::InvalidToken(token.get_lexed()), token.span)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lex::Lexer;
+
+    fn parse_recovering(src: &str) -> (Ast, Vec<ParseError>) {
+        let mut parser = Parser::new_recovering();
+        for tok in Lexer::new(src) {
+            let _ = parser.parse_token(tok.unwrap());
+        }
+        parser.finish_recovering()
+    }
+
+    fn parse(src: &str) -> Ast {
+        let mut parser = Parser::new();
+        for tok in Lexer::new(src) {
+            parser.parse_token(tok.unwrap()).unwrap();
+        }
+        parser.finish().unwrap()
+    }
+
+    #[test]
+    fn nested_forms_in_the_first_column_are_not_errors() {
+        let src = "(set (name f) (params x)\n(body\n(add (x) 1)))\n(f 1)\n";
+        let (tree, errors) = parse_recovering(src);
+        assert_eq!(errors, Vec::new());
+        assert!(tree == parse(src));
+        assert_eq!(tree.child_count(), 2);
+    }
+
+    #[test]
+    fn a_missing_close_is_reported_at_its_open() {
+        let (tree, errors) = parse_recovering("(add 1\n  (sub 2 1)\n");
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].kind, ParseErrorKind::Unclosed("(".to_string()));
+        assert_eq!((errors[0].span.line, errors[0].span.col), (1, 1));
+        assert_eq!(tree.child_count(), 0);
+    }
+
+    #[test]
+    fn skipping_after_an_error_stops_at_the_next_top_level_form() {
+        let (tree, errors) = parse_recovering("(add 1 >\n  (sub 2 1)\n(mul 2 3)\n");
+        assert_eq!(errors.len(), 1);
+        match errors[0].kind {
+            ParseErrorKind::MismatchedClose { .. } => (),
+            ref kind => panic!("expected a mismatched close, found {:?}", kind)
         }
+        assert_eq!(tree.child_count(), 1);
+        assert!(tree.children()[0] == parse("(mul 2 3)").children()[0]);
     }
 }
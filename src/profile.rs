@@ -0,0 +1,165 @@
+use intern::{Symbol, SymbolMap};
+use std::fmt::Write;
+
+// Counts for one function, in evaluation steps. Inclusive steps count the
+// calls it makes too, exclusive ones only its own. A recursive function's
+// inclusive steps are counted once, from its outermost call.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Stats {
+    pub calls: u64,
+    pub inclusive: u64,
+    pub exclusive: u64,
+    pub builtin: bool
+}
+
+// One distinct call stack, as a node in a tree of them.
+struct Node {
+    name: Symbol,
+    parent: usize,
+    children: SymbolMap<usize>,
+    steps: u64
+}
+
+struct Active {
+    name: Symbol,
+    node: usize,
+    // Total steps when the call was entered.
+    entered: u64
+}
+
+// Counts calls and steps per user function and builtin while Eval runs
+// with it. Each builtin call counts as one step of its own, as it does
+// against fuel.
+pub struct Profiler {
+    // The tree of call stacks, rooted at the program itself.
+    nodes: Vec<Node>,
+    stack: Vec<Active>,
+    // How many calls of each function are on the stack.
+    open: SymbolMap<u32>,
+    stats: SymbolMap<Stats>,
+    steps: u64
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Profiler {
+            nodes: vec![Node {
+                name: Symbol::intern("main"),
+                parent: 0,
+                children: SymbolMap::default(),
+                steps: 0
+            }],
+            stack: Vec::new(),
+            open: SymbolMap::default(),
+            stats: SymbolMap::default(),
+            steps: 0
+        }
+    }
+
+    pub fn enter(&mut self, name: Symbol, builtin: bool) {
+        let parent = self.stack.last().map_or(0, |active| active.node);
+        let node = match self.nodes[parent].children.get(&name) {
+            Some(&node) => node,
+            None => {
+                self.nodes.push(Node {
+                    name: name,
+                    parent: parent,
+                    children: SymbolMap::default(),
+                    steps: 0
+                });
+                let node = self.nodes.len() - 1;
+                self.nodes[parent].children.insert(name, node);
+                node
+            }
+        };
+        self.stack.push(Active {
+            name: name,
+            node: node,
+            entered: self.steps
+        });
+        *self.open.entry(name).or_insert(0) += 1;
+        let stats = self.stats.entry(name).or_default();
+        stats.calls += 1;
+        stats.builtin = builtin;
+    }
+
+    pub fn exit(&mut self) {
+        if let Some(active) = self.stack.pop() {
+            let open = self.open.get_mut(&active.name).unwrap();
+            *open -= 1;
+            if *open == 0 {
+                self.stats.get_mut(&active.name).unwrap().inclusive += self.steps - active.entered;
+            }
+        }
+    }
+
+    pub fn step(&mut self) {
+        self.steps += 1;
+        match self.stack.last() {
+            Some(active) => {
+                self.nodes[active.node].steps += 1;
+                self.stats.get_mut(&active.name).unwrap().exclusive += 1;
+            },
+            None => self.nodes[0].steps += 1
+        }
+    }
+
+    // Exits calls until only depth are still in progress, as after an error.
+    pub fn unwind_to(&mut self, depth: usize) {
+        while self.stack.len() > depth {
+            self.exit();
+        }
+    }
+
+    // Steps counted so far, wherever they were taken.
+    pub fn total_steps(&self) -> u64 {
+        self.steps
+    }
+
+    // Every function called, the most inclusive steps first.
+    pub fn stats(&self) -> Vec<(Symbol, &Stats)> {
+        let mut stats: Vec<(Symbol, &Stats)> = self.stats.iter().map(|(name, stats)| (*name, stats)).collect();
+        stats.sort_by(|a, b| b.1.inclusive.cmp(&a.1.inclusive)
+                      .then(b.1.exclusive.cmp(&a.1.exclusive))
+                      .then(a.0.as_str().cmp(b.0.as_str())));
+        stats
+    }
+
+    // A table of stats(), with each count also as a share of all steps.
+    pub fn report(&self) -> String {
+        let total = self.steps.max(1) as f64;
+        let mut report = format!("{} steps\n{:<20} {:>10} {:>18} {:>18}\n", self.steps, "function", "calls", "inclusive", "exclusive");
+        for (name, stats) in self.stats() {
+            let name = match stats.builtin {
+                true => format!("{} (builtin)", name),
+                false => name.to_string()
+            };
+            let _ = writeln!(report, "{:<20} {:>10} {:>10} {:>6.1}% {:>10} {:>6.1}%", name, stats.calls,
+                             stats.inclusive, stats.inclusive as f64 * 100.0 / total,
+                             stats.exclusive, stats.exclusive as f64 * 100.0 / total);
+        }
+        report
+    }
+
+    // One line per distinct call stack, such as `main;fib;fib;add 12`, with
+    // the steps taken in the innermost call. This is the folded format
+    // flamegraph tools read.
+    pub fn folded(&self) -> String {
+        let mut lines = Vec::new();
+        for (i, node) in self.nodes.iter().enumerate() {
+            if node.steps == 0 {
+                continue;
+            }
+            let mut names = vec![node.name.as_str()];
+            let mut at = i;
+            while at != 0 {
+                at = self.nodes[at].parent;
+                names.push(self.nodes[at].name.as_str());
+            }
+            names.reverse();
+            lines.push(format!("{} {}\n", names.join(";"), node.steps));
+        }
+        lines.sort();
+        lines.concat()
+    }
+}
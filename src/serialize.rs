@@ -0,0 +1,805 @@
+use ast::{Ast, AstTrait};
+use std::error::Error;
+use std::fmt;
+use std::str;
+use token::{Span, Token, TokenTrait, Type};
+
+// Bumped whenever either encoding changes shape. Decoding refuses any other
+// version rather than guessing.
+pub const FORMAT_VERSION: u64 = 1;
+
+const MAGIC: &[u8] = b"HKAST";
+
+// How deep a tree may nest below its root. Decoding recurses, so this keeps
+// hostile input from overflowing the stack. Encoding refuses deeper trees,
+// so whatever is written can be read back.
+pub const MAX_DEPTH: usize = 256;
+
+// A tree node takes two levels of JSON, its object and its children array,
+// and the whole tree sits in one more object.
+const MAX_JSON_DEPTH: usize = 2 * MAX_DEPTH + 3;
+
+// The name and byte each token type a tree can hold is written as. These
+// are the wire format, so they must never change, whatever the order of
+// Type. The other types only exist while lexing and parsing.
+const NODE_TYPES: &[(Type, &str, u8)] = &[
+    (Type::Oparen, "oparen", 1),
+    (Type::Func, "func", 3),
+    (Type::OpenList, "open_list", 4),
+    (Type::Number, "number", 6),
+    (Type::StrType, "str", 7)
+];
+
+fn type_name(tok_type: Type) -> &'static str {
+    NODE_TYPES.iter().find(|node| node.0 == tok_type).map_or("invalid", |node| node.1)
+}
+
+fn type_code(tok_type: Type) -> u8 {
+    NODE_TYPES.iter().find(|node| node.0 == tok_type).map_or(0xff, |node| node.2)
+}
+
+fn type_from_name(name: &str) -> Option<Type> {
+    NODE_TYPES.iter().find(|node| node.1 == name).map(|node| node.0)
+}
+
+fn type_from_code(code: u8) -> Option<Type> {
+    NODE_TYPES.iter().find(|node| node.2 == code).map(|node| node.0)
+}
+
+// Only the shapes the parser makes are written or read: a typeless node
+// only at the root, and children only under forms, lists and the root.
+fn tree_problem(tok_type: Option<Type>, depth: usize, children: usize) -> Option<String> {
+    match tok_type {
+        None if depth > 0 => Some("only the root can have no type".to_string()),
+        Some(tok_type) if children > 0 && tok_type != Type::Oparen && tok_type != Type::OpenList => {
+            Some(format!("a `{}` node can not have children", type_name(tok_type)))
+        },
+        _ => None
+    }
+}
+
+fn check_node(tok_type: Option<Type>, depth: usize, children: usize, offset: usize) -> Result<(), DecodeError> {
+    match tree_problem(tok_type, depth, children) {
+        Some(message) => Err(DecodeError::new(DecodeErrorKind::InvalidTree(message), offset)),
+        None => Ok(())
+    }
+}
+
+// The encoders' side of the same checks, along with the depth and the
+// token types a tree can hold.
+fn check_encodable(ast: &Ast, depth: usize) -> Result<(), EncodeError> {
+    if depth > MAX_DEPTH {
+        return Err(EncodeError::TooDeep);
+    }
+    if let Some(ref tok) = ast.node_val {
+        if NODE_TYPES.iter().all(|node| node.0 != tok.tok_type) {
+            return Err(EncodeError::InvalidTree(format!("a `{}` token can not be in a tree", tok.get_lexed())));
+        }
+    }
+    match tree_problem(ast.node_val.as_ref().map(|tok| tok.tok_type), depth, ast.child_count()) {
+        Some(message) => Err(EncodeError::InvalidTree(message)),
+        None => Ok(())
+    }
+}
+
+// Why a tree was not encoded. Only trees the decoders accept are written.
+#[derive(Clone, Debug, PartialEq)]
+pub enum EncodeError {
+    InvalidTree(String),
+    TooDeep
+}
+
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            EncodeError::InvalidTree(ref message) => write!(f, "{}", message),
+            EncodeError::TooDeep => write!(f, "tree nests deeper than {}", MAX_DEPTH)
+        }
+    }
+}
+
+impl Error for EncodeError {}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum DecodeErrorKind {
+    BadMagic,
+    UnsupportedVersion(u64),
+    UnexpectedEnd,
+    InvalidUtf8,
+    InvalidType(String),
+    InvalidJson(String),
+    // A node the parser would never produce where it is.
+    InvalidTree(String),
+    TooDeep,
+    TrailingInput
+}
+
+// offset is in bytes from the start of the input.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DecodeError {
+    pub kind: DecodeErrorKind,
+    pub offset: usize
+}
+
+impl DecodeError {
+    pub fn new(kind: DecodeErrorKind, offset: usize) -> Self {
+        DecodeError {
+            kind: kind,
+            offset: offset
+        }
+    }
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            DecodeErrorKind::BadMagic => write!(f, "not a serialized Hackit tree"),
+            DecodeErrorKind::UnsupportedVersion(version) => {
+                write!(f, "unsupported format version {}, expected {}", version, FORMAT_VERSION)
+            },
+            DecodeErrorKind::UnexpectedEnd => write!(f, "input ended early"),
+            DecodeErrorKind::InvalidUtf8 => write!(f, "lexeme at byte {} is not valid UTF-8", self.offset),
+            DecodeErrorKind::InvalidType(ref name) => write!(f, "invalid token type `{}` at byte {}", name, self.offset),
+            DecodeErrorKind::InvalidJson(ref message) => write!(f, "{} at byte {}", message, self.offset),
+            DecodeErrorKind::InvalidTree(ref message) => write!(f, "{} at byte {}", message, self.offset),
+            DecodeErrorKind::TooDeep => write!(f, "tree nests deeper than {} at byte {}", MAX_DEPTH, self.offset),
+            DecodeErrorKind::TrailingInput => write!(f, "unexpected input after the tree at byte {}", self.offset)
+        }
+    }
+}
+
+impl Error for DecodeError {}
+
+// JSON
+
+// {"version": 1, "tree": node}, where a node is
+// {"type": "oparen", "lexeme": "(", "span": {...}, "children": [...]}
+// and the root of a program has a null type, lexeme and span.
+pub fn to_json(ast: &Ast) -> Result<String, EncodeError> {
+    let mut json = format!("{{\"version\":{},\"tree\":", FORMAT_VERSION);
+    json_node(ast, 0, &mut json)?;
+    json.push('}');
+    Ok(json)
+}
+
+fn json_node(ast: &Ast, depth: usize, out: &mut String) -> Result<(), EncodeError> {
+    check_encodable(ast, depth)?;
+    match ast.node_val {
+        Some(ref tok) => {
+            out.push_str("{\"type\":");
+            json_string(type_name(tok.tok_type), out);
+            out.push_str(",\"lexeme\":");
+            json_string(&tok.get_lexed(), out);
+            out.push_str(&format!(",\"span\":{{\"start\":{},\"end\":{},\"line\":{},\"col\":{}}}",
+                                  tok.span.start, tok.span.end, tok.span.line, tok.span.col));
+        },
+        None => out.push_str("{\"type\":null,\"lexeme\":null,\"span\":null")
+    }
+    out.push_str(",\"children\":[");
+    for (i, child) in ast.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        json_node(child, depth + 1, out)?;
+    }
+    out.push_str("]}");
+    Ok(())
+}
+
+// Writes string as a quoted JSON string.
+pub fn json_string(string: &str, out: &mut String) {
+    out.push('"');
+    for c in string.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            '\r' => out.push_str("\\r"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c)
+        }
+    }
+    out.push('"');
+}
+
+pub fn from_json(json: &str) -> Result<Ast, DecodeError> {
+    let mut reader = JsonReader { src: json.as_bytes(), pos: 0, depth: 0 };
+    let value = reader.value()?;
+    reader.skip_whitespace();
+    if reader.pos != reader.src.len() {
+        return Err(DecodeError::new(DecodeErrorKind::TrailingInput, reader.pos));
+    }
+    match value.field("version")?.value {
+        Json::Number(version) if version == FORMAT_VERSION => (),
+        Json::Number(version) => return Err(DecodeError::new(DecodeErrorKind::UnsupportedVersion(version), 0)),
+        _ => return Err(value.field("version")?.invalid("`version` must be a number"))
+    }
+    ast_from_json(value.field("tree")?, 0)
+}
+
+fn ast_from_json(node: &Located, depth: usize) -> Result<Ast, DecodeError> {
+    let type_field = node.field("type")?;
+    let mut ast = match type_field.value {
+        Json::Null => Ast::new_null(),
+        Json::Str(ref name) => {
+            let tok_type = match type_from_name(name) {
+                Some(tok_type) => tok_type,
+                None => return Err(DecodeError::new(DecodeErrorKind::InvalidType(name.clone()), type_field.offset))
+            };
+            let lexeme = node.field("lexeme")?;
+            let mut tok = match lexeme.value {
+                Json::Str(ref lexed) => Token::new_preset(lexed.clone(), tok_type),
+                _ => return Err(lexeme.invalid("`lexeme` must be a string"))
+            };
+            let span = node.field("span")?;
+            tok.span = Span::new(span.field("start")?.number()?, span.field("end")?.number()?,
+                                 span.field("line")?.number()?, span.field("col")?.number()?);
+            Ast::new(tok)
+        },
+        _ => return Err(type_field.invalid("`type` must be a string or null"))
+    };
+    let children = node.field("children")?;
+    match children.value {
+        Json::Array(ref items) => {
+            check_node(ast.node_val.as_ref().map(|tok| tok.tok_type), depth, items.len(), node.offset)?;
+            for item in items.iter() {
+                ast.push_child(ast_from_json(item, depth + 1)?);
+            }
+        },
+        _ => return Err(children.invalid("`children` must be an array"))
+    }
+    Ok(ast)
+}
+
+// Only as much of JSON as the tree format needs: numbers are unsigned
+// integers.
+enum Json {
+    Null,
+    Number(u64),
+    Str(String),
+    Array(Vec<Located>),
+    Object(Vec<(String, Located)>)
+}
+
+struct Located {
+    value: Json,
+    offset: usize
+}
+
+impl Located {
+    fn invalid(&self, message: &str) -> DecodeError {
+        DecodeError::new(DecodeErrorKind::InvalidJson(message.to_string()), self.offset)
+    }
+
+    fn field(&self, name: &str) -> Result<&Located, DecodeError> {
+        match self.value {
+            Json::Object(ref fields) => {
+                match fields.iter().find(|field| field.0 == name) {
+                    Some(field) => Ok(&field.1),
+                    None => Err(self.invalid(&format!("missing field `{}`", name)))
+                }
+            },
+            _ => Err(self.invalid(&format!("expected an object with field `{}`", name)))
+        }
+    }
+
+    fn number(&self) -> Result<usize, DecodeError> {
+        match self.value {
+            Json::Number(number) => Ok(number as usize),
+            _ => Err(self.invalid("expected a number"))
+        }
+    }
+}
+
+struct JsonReader<'a> {
+    src: &'a [u8],
+    pos: usize,
+    // How many arrays and objects the reader is inside.
+    depth: usize
+}
+
+impl<'a> JsonReader<'a> {
+    fn error(&self, message: &str) -> DecodeError {
+        match self.pos < self.src.len() {
+            true => DecodeError::new(DecodeErrorKind::InvalidJson(message.to_string()), self.pos),
+            false => DecodeError::new(DecodeErrorKind::UnexpectedEnd, self.pos)
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.pos < self.src.len() && (self.src[self.pos] as char).is_ascii_whitespace() {
+            self.pos += 1;
+        }
+    }
+
+    fn peek(&mut self) -> Option<u8> {
+        self.skip_whitespace();
+        self.src.get(self.pos).cloned()
+    }
+
+    fn expect(&mut self, byte: u8) -> Result<(), DecodeError> {
+        match self.peek() {
+            Some(found) if found == byte => {
+                self.pos += 1;
+                Ok(())
+            },
+            _ => Err(self.error(&format!("expected `{}`", byte as char)))
+        }
+    }
+
+    fn value(&mut self) -> Result<Located, DecodeError> {
+        let first = self.peek();
+        let offset = self.pos;
+        if first == Some(b'[') || first == Some(b'{') {
+            if self.depth == MAX_JSON_DEPTH {
+                return Err(DecodeError::new(DecodeErrorKind::TooDeep, offset));
+            }
+            self.depth += 1;
+        }
+        let value = match first {
+            Some(b'n') => {
+                match self.src[self.pos..].starts_with(b"null") {
+                    true => {
+                        self.pos += 4;
+                        Json::Null
+                    },
+                    false => return Err(self.error("expected a value"))
+                }
+            },
+            Some(b'"') => Json::Str(self.string()?),
+            Some(b'[') => {
+                self.pos += 1;
+                let mut items = Vec::new();
+                if self.peek() == Some(b']') {
+                    self.pos += 1;
+                } else {
+                    loop {
+                        items.push(self.value()?);
+                        match self.peek() {
+                            Some(b',') => self.pos += 1,
+                            _ => break
+                        }
+                    }
+                    self.expect(b']')?;
+                }
+                self.depth -= 1;
+                Json::Array(items)
+            },
+            Some(b'{') => {
+                self.pos += 1;
+                let mut fields = Vec::new();
+                if self.peek() == Some(b'}') {
+                    self.pos += 1;
+                } else {
+                    loop {
+                        if self.peek() != Some(b'"') {
+                            return Err(self.error("expected a field name"));
+                        }
+                        let name = self.string()?;
+                        self.expect(b':')?;
+                        fields.push((name, self.value()?));
+                        match self.peek() {
+                            Some(b',') => self.pos += 1,
+                            _ => break
+                        }
+                    }
+                    self.expect(b'}')?;
+                }
+                self.depth -= 1;
+                Json::Object(fields)
+            },
+            Some(b'0'..=b'9') => {
+                let start = self.pos;
+                while self.pos < self.src.len() && self.src[self.pos].is_ascii_digit() {
+                    self.pos += 1;
+                }
+                let digits = str::from_utf8(&self.src[start..self.pos]).unwrap();
+                match digits.parse() {
+                    Ok(number) => Json::Number(number),
+                    Err(_) => return Err(DecodeError::new(DecodeErrorKind::InvalidJson("number is too large".to_string()), start))
+                }
+            },
+            _ => return Err(self.error("expected a value"))
+        };
+        Ok(Located {
+            value: value,
+            offset: offset
+        })
+    }
+
+    // Called with pos on the opening quote.
+    fn string(&mut self) -> Result<String, DecodeError> {
+        self.pos += 1;
+        let mut bytes = Vec::new();
+        loop {
+            let byte = match self.src.get(self.pos) {
+                Some(&byte) => byte,
+                None => return Err(self.error("unterminated string"))
+            };
+            self.pos += 1;
+            match byte {
+                b'"' => break,
+                b'\\' => {
+                    let escaped = match self.src.get(self.pos) {
+                        Some(&escaped) => escaped,
+                        None => return Err(self.error("unterminated string"))
+                    };
+                    self.pos += 1;
+                    let c = match escaped {
+                        b'"' => '"',
+                        b'\\' => '\\',
+                        b'/' => '/',
+                        b'n' => '\n',
+                        b't' => '\t',
+                        b'r' => '\r',
+                        b'b' => '\u{8}',
+                        b'f' => '\u{c}',
+                        b'u' => self.unicode_escape()?,
+                        _ => {
+                            self.pos -= 1;
+                            return Err(self.error("invalid escape"));
+                        }
+                    };
+                    let mut buf = [0; 4];
+                    bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+                },
+                _ => bytes.push(byte)
+            }
+        }
+        String::from_utf8(bytes).map_err(|_| DecodeError::new(DecodeErrorKind::InvalidUtf8, self.pos))
+    }
+
+    // Called with pos after the "\u". Surrogate pairs take a second \u.
+    fn unicode_escape(&mut self) -> Result<char, DecodeError> {
+        let high = self.hex4()?;
+        let code = match high {
+            0xd800..=0xdbff => {
+                if !self.src[self.pos..].starts_with(b"\\u") {
+                    return Err(self.error("unpaired surrogate"));
+                }
+                self.pos += 2;
+                let low = self.hex4()?;
+                if !(0xdc00..=0xdfff).contains(&low) {
+                    return Err(self.error("unpaired surrogate"));
+                }
+                0x10000 + ((high - 0xd800) << 10) + (low - 0xdc00)
+            },
+            _ => high
+        };
+        ::std::char::from_u32(code).ok_or_else(|| self.error("invalid unicode escape"))
+    }
+
+    fn hex4(&mut self) -> Result<u32, DecodeError> {
+        let digits = self.src.get(self.pos..self.pos + 4).and_then(|digits| str::from_utf8(digits).ok());
+        match digits.and_then(|digits| u32::from_str_radix(digits, 16).ok()) {
+            Some(code) => {
+                self.pos += 4;
+                Ok(code)
+            },
+            None => Err(self.error("invalid unicode escape"))
+        }
+    }
+}
+
+// Binary
+
+// The magic bytes and a LEB128 version, then the tree. Each node is a type
+// byte (0 for the root of a program, otherwise from NODE_TYPES),
+// and for tokens the lexeme's length and bytes and the four span fields,
+// followed by the child count and the children. Every integer is LEB128.
+pub fn to_bytes(ast: &Ast) -> Result<Vec<u8>, EncodeError> {
+    let mut bytes = MAGIC.to_vec();
+    write_varint(FORMAT_VERSION, &mut bytes);
+    bytes_node(ast, 0, &mut bytes)?;
+    Ok(bytes)
+}
+
+fn bytes_node(ast: &Ast, depth: usize, out: &mut Vec<u8>) -> Result<(), EncodeError> {
+    check_encodable(ast, depth)?;
+    match ast.node_val {
+        Some(ref tok) => {
+            out.push(type_code(tok.tok_type));
+            let lexed = tok.get_lexed();
+            write_varint(lexed.len() as u64, out);
+            out.extend_from_slice(lexed.as_bytes());
+            for field in [tok.span.start, tok.span.end, tok.span.line, tok.span.col].iter() {
+                write_varint(*field as u64, out);
+            }
+        },
+        None => out.push(0)
+    }
+    write_varint(ast.child_count() as u64, out);
+    for child in ast.iter() {
+        bytes_node(child, depth + 1, out)?;
+    }
+    Ok(())
+}
+
+fn write_varint(mut int: u64, out: &mut Vec<u8>) {
+    loop {
+        let byte = (int & 0x7f) as u8;
+        int >>= 7;
+        match int {
+            0 => {
+                out.push(byte);
+                return;
+            },
+            _ => out.push(byte | 0x80)
+        }
+    }
+}
+
+pub fn from_bytes(bytes: &[u8]) -> Result<Ast, DecodeError> {
+    if !bytes.starts_with(MAGIC) {
+        return Err(DecodeError::new(DecodeErrorKind::BadMagic, 0));
+    }
+    let mut reader = ByteReader { src: bytes, pos: MAGIC.len() };
+    let version = reader.varint()?;
+    if version != FORMAT_VERSION {
+        return Err(DecodeError::new(DecodeErrorKind::UnsupportedVersion(version), MAGIC.len()));
+    }
+    let ast = reader.node(0)?;
+    match reader.pos == bytes.len() {
+        true => Ok(ast),
+        false => Err(DecodeError::new(DecodeErrorKind::TrailingInput, reader.pos))
+    }
+}
+
+struct ByteReader<'a> {
+    src: &'a [u8],
+    pos: usize
+}
+
+impl<'a> ByteReader<'a> {
+    fn byte(&mut self) -> Result<u8, DecodeError> {
+        match self.src.get(self.pos) {
+            Some(&byte) => {
+                self.pos += 1;
+                Ok(byte)
+            },
+            None => Err(DecodeError::new(DecodeErrorKind::UnexpectedEnd, self.pos))
+        }
+    }
+
+    fn varint(&mut self) -> Result<u64, DecodeError> {
+        let mut int = 0;
+        let mut shift = 0;
+        loop {
+            let byte = self.byte()?;
+            if shift < 64 {
+                int |= ((byte & 0x7f) as u64) << shift;
+            }
+            shift += 7;
+            if byte & 0x80 == 0 {
+                return Ok(int);
+            }
+        }
+    }
+
+    fn node(&mut self, depth: usize) -> Result<Ast, DecodeError> {
+        let offset = self.pos;
+        if depth > MAX_DEPTH {
+            return Err(DecodeError::new(DecodeErrorKind::TooDeep, offset));
+        }
+        let mut ast = match self.byte()? {
+            0 => Ast::new_null(),
+            code => {
+                let tok_type = match type_from_code(code) {
+                    Some(tok_type) => tok_type,
+                    None => return Err(DecodeError::new(DecodeErrorKind::InvalidType(code.to_string()), offset))
+                };
+                let len = self.varint()? as usize;
+                let start = self.pos;
+                let lexed = match self.src.get(start..start.saturating_add(len)) {
+                    Some(lexed) => lexed,
+                    None => return Err(DecodeError::new(DecodeErrorKind::UnexpectedEnd, self.src.len()))
+                };
+                let lexed = match str::from_utf8(lexed) {
+                    Ok(lexed) => lexed.to_string(),
+                    Err(_) => return Err(DecodeError::new(DecodeErrorKind::InvalidUtf8, start))
+                };
+                self.pos += len;
+                let mut tok = Token::new_preset(lexed, tok_type);
+                tok.span = Span::new(self.varint()? as usize, self.varint()? as usize,
+                                     self.varint()? as usize, self.varint()? as usize);
+                Ast::new(tok)
+            }
+        };
+        let count = self.varint()?;
+        check_node(ast.node_val.as_ref().map(|tok| tok.tok_type), depth, count as usize, offset)?;
+        for _ in 0..count {
+            ast.push_child(self.node(depth + 1)?);
+        }
+        Ok(ast)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use build;
+    use lex::Lexer;
+    use parse::{Parser, ParserTrait};
+
+    // Every type a tree holds, with escapes and multibyte chars in strings,
+    // across more than one line.
+    const SOURCE: &str = "(set (name f) (params x)\n  (body <1 -2.5e3 0x1f \"q\\\"\\\\\\n\\u{1}\" (x) é>))\n(f 1)";
+
+    fn parse(src: &str) -> Ast {
+        let mut parser = Parser::new();
+        for tok in Lexer::new(src) {
+            parser.parse_token(tok.unwrap()).unwrap();
+        }
+        parser.finish().unwrap()
+    }
+
+    fn types(ast: &Ast, found: &mut Vec<Type>) {
+        if let Some(ref tok) = ast.node_val {
+            if !found.contains(&tok.tok_type) {
+                found.push(tok.tok_type);
+            }
+        }
+        for child in ast.iter() {
+            types(child, found);
+        }
+    }
+
+    // Token equality leaves spans out, so they are compared here.
+    fn same(a: &Ast, b: &Ast) -> bool {
+        let spans = |ast: &Ast| ast.node_val.as_ref().map(|tok| tok.span);
+        a == b && spans(a) == spans(b) && a.iter().zip(b.iter()).all(|(a, b)| same(a, b))
+    }
+
+    fn kind(result: Result<Ast, DecodeError>) -> DecodeErrorKind {
+        result.err().expect("decoding should fail").kind
+    }
+
+    fn bytes_tree(tail: &[u8]) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        write_varint(FORMAT_VERSION, &mut bytes);
+        bytes.extend_from_slice(tail);
+        bytes
+    }
+
+    #[test]
+    fn source_has_every_node_type() {
+        let mut found = Vec::new();
+        types(&parse(SOURCE), &mut found);
+        for node in NODE_TYPES.iter() {
+            assert!(found.contains(&node.0), "missing {}", node.1);
+        }
+    }
+
+    #[test]
+    fn json_round_trips() {
+        let ast = parse(SOURCE);
+        let decoded = from_json(&to_json(&ast).unwrap()).unwrap();
+        assert!(same(&decoded, &ast));
+        assert_ne!(decoded.child(1).unwrap().node_val.as_ref().unwrap().span, Span::default());
+        assert_eq!(to_json(&decoded).unwrap(), to_json(&ast).unwrap());
+    }
+
+    #[test]
+    fn bytes_round_trip() {
+        let ast = parse(SOURCE);
+        let decoded = from_bytes(&to_bytes(&ast).unwrap()).unwrap();
+        assert!(same(&decoded, &ast));
+        assert_eq!(to_bytes(&decoded).unwrap(), to_bytes(&ast).unwrap());
+    }
+
+    #[test]
+    fn single_token_and_empty_trees_round_trip() {
+        let mut number = build::IntoAst::into_ast(42);
+        number.node_val.as_mut().unwrap().span = Span::new(3, 5, 2, 4);
+        for ast in [number, Ast::new_null()].iter() {
+            assert!(same(&from_json(&to_json(ast).unwrap()).unwrap(), ast));
+            assert!(same(&from_bytes(&to_bytes(ast).unwrap()).unwrap(), ast));
+        }
+    }
+
+    #[test]
+    fn rejects_bad_magic() {
+        let mut bytes = to_bytes(&parse(SOURCE)).unwrap();
+        bytes[0] = b'X';
+        assert_eq!(kind(from_bytes(&bytes)), DecodeErrorKind::BadMagic);
+        assert_eq!(kind(from_bytes(b"HK")), DecodeErrorKind::BadMagic);
+    }
+
+    #[test]
+    fn rejects_other_versions() {
+        let bytes = bytes_tree(&[]);
+        let mut other = MAGIC.to_vec();
+        write_varint(FORMAT_VERSION + 1, &mut other);
+        other.extend_from_slice(&bytes[other.len()..]);
+        assert_eq!(kind(from_bytes(&other)), DecodeErrorKind::UnsupportedVersion(FORMAT_VERSION + 1));
+
+        let json = to_json(&parse(SOURCE)).unwrap().replacen(&format!("\"version\":{}", FORMAT_VERSION), "\"version\":99", 1);
+        assert_eq!(kind(from_json(&json)), DecodeErrorKind::UnsupportedVersion(99));
+    }
+
+    #[test]
+    fn rejects_truncated_input() {
+        let bytes = to_bytes(&parse(SOURCE)).unwrap();
+        for len in MAGIC.len()..bytes.len() {
+            assert_eq!(kind(from_bytes(&bytes[..len])), DecodeErrorKind::UnexpectedEnd, "at {}", len);
+        }
+        let json = to_json(&parse(SOURCE)).unwrap();
+        for len in 0..json.len() {
+            if json.is_char_boundary(len) {
+                assert!(from_json(&json[..len]).is_err(), "at {}", len);
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_trailing_input() {
+        let mut bytes = to_bytes(&parse(SOURCE)).unwrap();
+        bytes.push(0);
+        assert_eq!(kind(from_bytes(&bytes)), DecodeErrorKind::TrailingInput);
+        let json = format!("{} {{}}", to_json(&parse(SOURCE)).unwrap());
+        assert_eq!(kind(from_json(&json)), DecodeErrorKind::TrailingInput);
+    }
+
+    #[test]
+    fn rejects_typeless_nodes_below_the_root() {
+        let json = "{\"version\":1,\"tree\":{\"type\":\"oparen\",\"lexeme\":\"(\",\"span\":{\"start\":0,\"end\":1,\"line\":1,\
+                    \"col\":1},\"children\":[{\"type\":null,\"lexeme\":null,\"span\":null,\"children\":[]}]}}";
+        assert!(matches!(kind(from_json(json)), DecodeErrorKind::InvalidTree(_)));
+        assert!(matches!(kind(from_bytes(&bytes_tree(&[0, 1, 0, 0]))), DecodeErrorKind::InvalidTree(_)));
+    }
+
+    #[test]
+    fn rejects_tokens_trees_do_not_hold() {
+        let json = to_json(&parse("(f)")).unwrap().replace("\"type\":\"oparen\"", "\"type\":\"cparen\"");
+        assert_eq!(kind(from_json(&json)), DecodeErrorKind::InvalidType("cparen".to_string()));
+        for code in [2, 5, 8, 9, 10, 11, 0xff].iter() {
+            let bytes = bytes_tree(&[*code, 1, b')', 0, 0, 0, 0, 0]);
+            assert_eq!(kind(from_bytes(&bytes)), DecodeErrorKind::InvalidType(code.to_string()));
+        }
+    }
+
+    #[test]
+    fn rejects_children_under_leaves() {
+        let bytes = bytes_tree(&[3, 1, b'f', 0, 0, 0, 0, 1, 3, 1, b'g', 0, 0, 0, 0, 0]);
+        assert!(matches!(kind(from_bytes(&bytes)), DecodeErrorKind::InvalidTree(_)));
+    }
+
+    #[test]
+    fn accepts_nesting_up_to_max_depth() {
+        let nest = |depth: usize| {
+            let mut ast = build::sym("x");
+            for _ in 1..depth {
+                ast = build::form(vec![ast]);
+            }
+            build::program(vec![ast])
+        };
+        let deepest = nest(MAX_DEPTH);
+        assert!(from_json(&to_json(&deepest).unwrap()).unwrap() == deepest);
+        assert!(from_bytes(&to_bytes(&deepest).unwrap()).unwrap() == deepest);
+        assert_eq!(to_json(&nest(MAX_DEPTH + 1)), Err(EncodeError::TooDeep));
+        assert_eq!(to_bytes(&nest(MAX_DEPTH + 1)), Err(EncodeError::TooDeep));
+    }
+
+    #[test]
+    fn refuses_to_encode_what_can_not_be_decoded() {
+        let typeless = build::form(vec![build::sym("f"), Ast::new_null()]);
+        let mut leaf = build::sym("f");
+        leaf.push_child(build::sym("g"));
+        let close = Ast::new(Token::new_preset(")".to_string(), Type::Cparen));
+        for ast in [typeless, build::program(vec![leaf]), build::program(vec![build::form(vec![close])])].iter() {
+            assert!(matches!(to_json(ast), Err(EncodeError::InvalidTree(_))));
+            assert!(matches!(to_bytes(ast), Err(EncodeError::InvalidTree(_))));
+        }
+    }
+
+    #[test]
+    fn rejects_deep_nesting_without_overflowing() {
+        assert_eq!(kind(from_json(&"[".repeat(200_000))), DecodeErrorKind::TooDeep);
+        let mut bytes = bytes_tree(&[]);
+        for _ in 0..500_000 {
+            bytes.extend_from_slice(&[1, 1, b'(', 0, 0, 0, 0, 1]);
+        }
+        assert_eq!(kind(from_bytes(&bytes)), DecodeErrorKind::TooDeep);
+    }
+}
@@ -1,3 +1,5 @@
+use intern::Symbol;
+use std::cmp::Ordering;
 use std::string::String;
 
 #[derive(Copy, Clone, PartialEq, Eq, Ord, PartialOrd)]
@@ -9,6 +11,7 @@ pub enum Type {
     CloseList,
     Number,
     StrType,
+    Comment,
     Space,
     Empty,
     Error
@@ -22,6 +25,43 @@ impl Type {
             _ => false
         }
     }
+
+    pub fn closing_lexeme(&self) -> &'static str {
+        match *self {
+            Type::Oparen => ")",
+            Type::OpenList => ">",
+            _ => ""
+        }
+    }
+}
+
+// Byte offsets into the source plus the 1-based line and column (in chars)
+// of the first char. Tokens made up by the interpreter have line 0.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+    pub col: usize
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize, line: usize, col: usize) -> Self {
+        Span {
+            start: start,
+            end: end,
+            line: line,
+            col: col
+        }
+    }
+
+    pub fn is_dummy(&self) -> bool {
+        self.line == 0
+    }
+
+    pub fn to(&self, other: Span) -> Span {
+        Span::new(self.start, other.end, self.line, self.col)
+    }
 }
 
 #[derive(Copy, Clone)]
@@ -34,12 +74,37 @@ pub enum LexResult {
     Fail
 }
 
-#[derive(Clone, Eq, PartialEq, Ord, PartialOrd)]
+#[derive(Clone)]
 pub struct Token {
     lexed: String,
     pub tok_type: Type,
+    pub span: Span,
     is_lexed: bool,
-    must_append: bool
+    must_append: bool,
+    // The name, for a name token once it is parsed.
+    symbol: Option<Symbol>
+}
+
+// Spans are left out so the same code parsed in two places compares equal.
+impl PartialEq for Token {
+    fn eq(&self, other: &Token) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Token {}
+
+impl PartialOrd for Token {
+    fn partial_cmp(&self, other: &Token) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Token {
+    fn cmp(&self, other: &Token) -> Ordering {
+        (&self.lexed, self.tok_type, self.is_lexed, self.must_append)
+            .cmp(&(&other.lexed, other.tok_type, other.is_lexed, other.must_append))
+    }
 }
 
 pub trait TokenTrait {
@@ -63,7 +128,17 @@ impl TokenTrait for Token {
         } else if self.must_append {
             self.push_escape(new_char)
         } else {
-            let res = transitions::apply_transition(self, new_char);
+            let mut res = transitions::apply_transition(self, new_char);
+            if self.tok_type == Type::Number {
+                match res {
+                    LexResult::Fail if transitions::is_bare_sign(&self.lexed) => {
+                        self.tok_type = Type::Func;
+                        res = transitions::apply_transition(self, new_char);
+                    },
+                    LexResult::FinishNew => self.finish(),
+                    _ => ()
+                }
+            }
             self.parse_lexresult(res, new_char);
             res
         }
@@ -75,8 +150,10 @@ impl Token {
         let mut tok = Token {
             lexed: String::new(),
             tok_type: Type::Empty,
+            span: Span::default(),
             is_lexed: false,
-            must_append: false
+            must_append: false,
+            symbol: None
         };
         let first = transitions::first_transition(lexed);
         tok.tok_type = first.1;
@@ -85,11 +162,51 @@ impl Token {
     }
 
     pub fn new_preset(lexed: String, tok_type: Type) -> Self {
-        Token {
+        let mut tok = Token {
             lexed: lexed,
             tok_type: tok_type,
+            span: Span::default(),
             is_lexed: true,
-            must_append: false
+            must_append: false,
+            symbol: None
+        };
+        tok.intern();
+        tok
+    }
+
+    // Interns a name token's name, so it is only looked up once however
+    // often the tree is lowered.
+    pub fn intern(&mut self) {
+        if self.tok_type == Type::Func && self.symbol.is_none() {
+            self.symbol = Some(Symbol::intern(&self.lexed));
+        }
+    }
+
+    // The name of a name token.
+    pub fn symbol(&self) -> Symbol {
+        self.symbol.unwrap_or_else(|| Symbol::intern(&self.lexed))
+    }
+
+    // The token as it would be written in source, with strings quoted and
+    // escaped again.
+    pub fn to_source(&self) -> String {
+        match self.tok_type {
+            Type::StrType => {
+                let mut source = String::from("\"");
+                for c in self.lexed.chars() {
+                    match c {
+                        '"' => source.push_str("\\\""),
+                        '\\' => source.push_str("\\\\"),
+                        '\n' => source.push_str("\\n"),
+                        '\t' => source.push_str("\\t"),
+                        '\r' => source.push_str("\\r"),
+                        _ => source.push(c)
+                    }
+                }
+                source.push('"');
+                source
+            },
+            _ => self.lexed.clone()
         }
     }
 
@@ -97,6 +214,17 @@ impl Token {
         self.lexed = string;
     }
 
+    // Called once the token can not grow any further, either because a
+    // delimiter followed it or because the input ran out.
+    pub fn finish(&mut self) {
+        if self.tok_type == Type::Number && !transitions::is_number(&self.lexed) {
+            self.tok_type = match transitions::is_bare_sign(&self.lexed) {
+                true => Type::Func,
+                false => Type::Error
+            };
+        }
+    }
+
     fn make_error(&mut self) {
         self.tok_type = Type::Error;
         self.status_true();
@@ -142,14 +270,62 @@ impl Token {
     }
 }
 
+pub fn parse_int(num_str: &str) -> Option<i64> {
+    if !transitions::is_number(num_str) {
+        return None;
+    }
+    let cleaned: String = num_str.chars().filter(|c| *c != '_').collect();
+    let (sign, unsigned) = match cleaned.chars().next() {
+        Some(c) if c == '-' || c == '+' => (c.to_string(), cleaned[1..].to_string()),
+        _ => (String::new(), cleaned)
+    };
+    let (radix, digits) = match unsigned.get(0..2) {
+        Some("0x") | Some("0X") => (16, &unsigned[2..]),
+        Some("0o") | Some("0O") => (8, &unsigned[2..]),
+        Some("0b") | Some("0B") => (2, &unsigned[2..]),
+        _ => (10, &unsigned[..])
+    };
+    i64::from_str_radix(&(sign + digits), radix).ok()
+}
+
+pub fn parse_float(num_str: &str) -> Option<f64> {
+    if !transitions::is_number(num_str) {
+        return None;
+    }
+    match parse_int(num_str) {
+        Some(int) => Some(int as f64),
+        None => {
+            let cleaned: String = num_str.chars().filter(|c| *c != '_').collect();
+            cleaned.parse::<f64>().ok()
+        }
+    }
+}
+
 mod transitions {
     use token::{LexResult, Token, TokenTrait, Type};
 
+    #[derive(Copy, Clone, PartialEq)]
+    enum NumState {
+        Start,
+        Sign,
+        Zero,
+        RadixPrefix(u32),
+        Radix(u32),
+        Int,
+        LeadingDot,
+        Dot,
+        Frac,
+        Exp,
+        ExpSign,
+        ExpInt
+    }
+
     pub fn apply_transition(tok: &Token, to_lex: char) -> LexResult {
         match tok.tok_type {
             Type::Func => func(to_lex),
             Type::Number => number(tok.get_lexed(), to_lex),
             Type::StrType => str_type(to_lex),
+            Type::Comment => comment(to_lex),
             _ => LexResult::Fail
         }
     }
@@ -159,11 +335,12 @@ mod transitions {
             '"' => (LexResult::Continue, Type::StrType),
             '(' => (LexResult::Finish, Type::Oparen),
             ')' => (LexResult::Finish, Type::Cparen),
-            ' ' => (LexResult::Finish, Type::Space),
-            '0'...'9' | '.' | '-' => (LexResult::Pass, Type::Number),
+            ' ' | '\n' | '\t' | '\r' => (LexResult::Finish, Type::Space),
+            '0'..='9' | '.' | '-' | '+' => (LexResult::Pass, Type::Number),
             '<' => (LexResult::Finish, Type::OpenList),
+            ';' => (LexResult::Pass, Type::Comment),
             '>' => (LexResult::Finish, Type::CloseList),
-            '\0' | '\n' => (LexResult::Fail, Type::Error),
+            '\0' => (LexResult::Fail, Type::Error),
             _ => (LexResult::Pass, Type::Func)
         }
     }
@@ -171,7 +348,7 @@ mod transitions {
     fn func(to_lex: char) -> LexResult {
         if is_delimiter(to_lex) {
             LexResult::FinishNew
-        } else if to_lex == '\n' || to_lex == '\r' || to_lex == '\t' {
+        } else if to_lex == '\0' {
             LexResult::Fail
         } else {
             LexResult::Pass
@@ -179,20 +356,24 @@ mod transitions {
     }
 
     fn number(cur_lexed: String, to_lex: char) -> LexResult {
-        if to_lex == '-' {
-            LexResult::Fail
-        } else if to_lex == '.' {
-            if check_decimal(cur_lexed) {
-                LexResult::Fail
-            } else {
-                LexResult::Pass
+        if is_delimiter(to_lex) {
+            LexResult::FinishNew
+        } else {
+            let mut candidate = cur_lexed;
+            candidate.push(to_lex);
+            match scan_number(&candidate) {
+                Some(_) => LexResult::Pass,
+                None => LexResult::Fail
             }
-        } else if '0' <= to_lex && to_lex <= '9'  {
-            LexResult::Pass
-        } else if is_delimiter(to_lex) {
+        }
+    }
+
+    // Comments run to the end of the line.
+    fn comment(to_lex: char) -> LexResult {
+        if to_lex == '\n' {
             LexResult::FinishNew
         } else {
-            LexResult::Fail
+            LexResult::Pass
         }
     }
 
@@ -206,21 +387,146 @@ mod transitions {
         }
     }
 
-    fn check_decimal(num_str: String) -> bool {
-        let mut has_decimal = false;
+    // A sign or a decimal point on its own is a name such as `-`, not a number.
+    pub fn is_bare_sign(num_str: &str) -> bool {
+        !num_str.is_empty() && num_str.chars().all(|c| c == '-' || c == '+' || c == '.')
+    }
+
+    pub fn is_number(num_str: &str) -> bool {
+        match scan_number(num_str) {
+            Some((state, trailing_separator)) => {
+                !trailing_separator && match state {
+                    NumState::Zero | NumState::Radix(_) | NumState::Int |
+                    NumState::Dot | NumState::Frac | NumState::ExpInt => true,
+                    _ => false
+                }
+            },
+            None => false
+        }
+    }
+
+    // Walks the number grammar, returning the state reached and whether the
+    // last character was a `_` digit separator, or None if num_str can not be
+    // the start of a number.
+    fn scan_number(num_str: &str) -> Option<(NumState, bool)> {
+        let mut state = NumState::Start;
+        let mut after_digit = false;
+        let mut trailing_separator = false;
         for digit in num_str.chars() {
-            if digit == '.' {
-                has_decimal = true;
-                break;
+            if digit == '_' {
+                if !after_digit {
+                    return None;
+                }
+                after_digit = false;
+                trailing_separator = true;
+                continue;
             }
+            let is_digit = match state {
+                NumState::RadixPrefix(radix) | NumState::Radix(radix) => digit.is_digit(radix),
+                _ => digit.is_ascii_digit()
+            };
+            if trailing_separator && !is_digit {
+                return None;
+            }
+            state = match (state, digit) {
+                (NumState::Start, '-') | (NumState::Start, '+') => NumState::Sign,
+                (NumState::Start, '0') | (NumState::Sign, '0') => NumState::Zero,
+                (NumState::Start, '.') | (NumState::Sign, '.') => NumState::LeadingDot,
+                (NumState::Start, _) | (NumState::Sign, _) if is_digit => NumState::Int,
+                (NumState::Zero, 'x') | (NumState::Zero, 'X') => NumState::RadixPrefix(16),
+                (NumState::Zero, 'o') | (NumState::Zero, 'O') => NumState::RadixPrefix(8),
+                (NumState::Zero, 'b') | (NumState::Zero, 'B') => NumState::RadixPrefix(2),
+                (NumState::RadixPrefix(radix), _) | (NumState::Radix(radix), _) if is_digit => NumState::Radix(radix),
+                (NumState::Zero, _) | (NumState::Int, _) if is_digit => NumState::Int,
+                (NumState::Zero, '.') | (NumState::Int, '.') => NumState::Dot,
+                (NumState::LeadingDot, _) | (NumState::Dot, _) | (NumState::Frac, _) if is_digit => NumState::Frac,
+                (NumState::Zero, 'e') | (NumState::Int, 'e') | (NumState::Dot, 'e') | (NumState::Frac, 'e') |
+                (NumState::Zero, 'E') | (NumState::Int, 'E') | (NumState::Dot, 'E') | (NumState::Frac, 'E') => NumState::Exp,
+                (NumState::Exp, '-') | (NumState::Exp, '+') => NumState::ExpSign,
+                (NumState::Exp, _) | (NumState::ExpSign, _) | (NumState::ExpInt, _) if is_digit => NumState::ExpInt,
+                _ => return None
+            };
+            after_digit = is_digit;
+            trailing_separator = false;
         }
-        has_decimal
+        Some((state, trailing_separator))
     }
 
     fn is_delimiter(delim: char) -> bool {
         match delim {
-            ' ' | ')' | '|' => true,
+            ' ' | '\n' | '\t' | '\r' | ')' | '>' | '|' => true,
             _ => false
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lex::{LexErrorKind, Lexer};
+
+    // The tokens lexed from src inside a form, without the parens.
+    fn lex(src: &str) -> Result<Vec<Token>, LexErrorKind> {
+        match Lexer::new(&format!("({})", src)).collect::<Result<Vec<Token>, _>>() {
+            Ok(toks) => Ok(toks[1..toks.len() - 1].to_vec()),
+            Err(err) => Err(err.kind)
+        }
+    }
+
+    fn lexes_as(src: &str, tok_type: Type) {
+        let toks = lex(src).unwrap_or_else(|err| panic!("`{}` failed to lex: {:?}", src, err));
+        assert_eq!(toks.len(), 1, "`{}`", src);
+        assert!(toks[0].tok_type == tok_type && toks[0].get_lexed() == src, "`{}`", src);
+    }
+
+    #[test]
+    fn integers_in_every_radix() {
+        let cases: &[(&str, i64)] = &[
+            ("0", 0), ("007", 7), ("-5", -5), ("+5", 5), ("1_000_000", 1000000),
+            ("0x1F", 31), ("0XfF", 255), ("-0x10", -16), ("0b101", 5), ("0B1_0", 2), ("0o17", 15),
+            ("9223372036854775807", i64::MAX), ("-9223372036854775808", i64::MIN)
+        ];
+        for &(src, int) in cases.iter() {
+            lexes_as(src, Type::Number);
+            assert_eq!(parse_int(src), Some(int), "`{}`", src);
+            assert_eq!(parse_float(src), Some(int as f64), "`{}`", src);
+        }
+    }
+
+    #[test]
+    fn floats_with_dots_and_exponents() {
+        let cases: &[(&str, f64)] = &[
+            (".5", 0.5), ("-.5", -0.5), ("1.", 1.0), ("1.25", 1.25), ("1e3", 1000.0), ("1E+3", 1000.0),
+            ("2.5e-3", 0.0025), ("-1.5e2", -150.0), ("1_000.000_1", 1000.0001), ("9223372036854775808", 9223372036854775808.0)
+        ];
+        for &(src, float) in cases.iter() {
+            lexes_as(src, Type::Number);
+            assert_eq!(parse_int(src), None, "`{}`", src);
+            assert_eq!(parse_float(src), Some(float), "`{}`", src);
+        }
+    }
+
+    #[test]
+    fn malformed_numbers_are_lex_errors() {
+        for src in ["1e", "1e+", "1__0", "1_", "0x", "0x_1", "0b102", "0o8", "1.2.3", "1._5", "1a", "0x1g"].iter() {
+            match lex(src) {
+                Err(LexErrorKind::InvalidNumber(ref lexed)) => assert!(src.starts_with(lexed.as_str()), "`{}`", src),
+                other => panic!("`{}` lexed as {:?}", src, other.map(|toks| toks.len()))
+            }
+            assert_eq!((parse_int(src), parse_float(src)), (None, None), "`{}`", src);
+        }
+    }
+
+    #[test]
+    fn signs_and_separators_alone_start_names() {
+        for src in ["-", "+", "_1", "-abc", "+x"].iter() {
+            lexes_as(src, Type::Func);
+        }
+    }
+
+    #[test]
+    fn integers_too_big_for_any_value_do_not_parse() {
+        lexes_as("0xffffffffffffffff", Type::Number);
+        assert_eq!((parse_int("0xffffffffffffffff"), parse_float("0xffffffffffffffff")), (None, None));
+    }
+}
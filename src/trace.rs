@@ -0,0 +1,216 @@
+use eval::RuntimeError;
+use intern::Symbol;
+use serialize::json_string;
+use std::cell::RefCell;
+use std::io::Write;
+use std::rc::Rc;
+use value::Value;
+
+// A special form as Eval carries it out.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SpecialForm<'a> {
+    Set(Symbol),
+    Let(Symbol, &'a Value),
+    // Whether the then branch was taken.
+    If(bool)
+}
+
+// Told about everything Eval does, as it does it. Depth is how many user
+// function calls are in progress around the event, so a call and its exit
+// share a depth and the calls it makes are one deeper. Builtin calls are
+// entered and exited like user ones. A call cut short by an error is never
+// exited, the error is reported instead.
+pub trait Tracer {
+    fn enter(&mut self, _name: Symbol, _args: &[Value], _depth: usize) {}
+
+    fn exit(&mut self, _name: Symbol, _result: &Value, _depth: usize) {}
+
+    fn special_form(&mut self, _form: &SpecialForm, _depth: usize) {}
+
+    fn error(&mut self, _err: &RuntimeError, _depth: usize) {}
+}
+
+// Shared so the host can still get at the tracer while Eval holds it.
+pub type SharedTracer = Rc<RefCell<dyn Tracer>>;
+
+// Prints calls as an indented tree, with results on the call's own line
+// when it made no calls of its own:
+//
+//   (fib 2)
+//     (eq 2 0) => false
+//     ...
+//   => 1
+pub struct TreeTracer<W: Write> {
+    out: W,
+    // A call not known to be a leaf yet.
+    pending: Option<String>
+}
+
+impl<W: Write> TreeTracer<W> {
+    pub fn new(out: W) -> Self {
+        TreeTracer {
+            out: out,
+            pending: None
+        }
+    }
+
+    fn flush_pending(&mut self) {
+        if let Some(line) = self.pending.take() {
+            let _ = writeln!(self.out, "{}", line);
+        }
+    }
+
+    fn line(&mut self, depth: usize, text: String) {
+        self.flush_pending();
+        let _ = writeln!(self.out, "{}{}", indent(depth), text);
+    }
+}
+
+impl<W: Write> Tracer for TreeTracer<W> {
+    fn enter(&mut self, name: Symbol, args: &[Value], depth: usize) {
+        self.flush_pending();
+        let mut line = format!("{}({}", indent(depth), name);
+        for arg in args.iter() {
+            line.push_str(&format!(" {}", arg));
+        }
+        line.push(')');
+        self.pending = Some(line);
+    }
+
+    fn exit(&mut self, _name: Symbol, result: &Value, depth: usize) {
+        match self.pending.take() {
+            Some(line) => {
+                let _ = writeln!(self.out, "{} => {}", line, result);
+            },
+            None => self.line(depth, format!("=> {}", result))
+        }
+    }
+
+    fn special_form(&mut self, form: &SpecialForm, depth: usize) {
+        let text = match *form {
+            SpecialForm::Set(name) => format!("set {}", name),
+            SpecialForm::Let(name, val) => format!("let {} = {}", name, val),
+            SpecialForm::If(then) => format!("if -> {}", match then {
+                true => "then",
+                false => "else"
+            })
+        };
+        self.line(depth, text);
+    }
+
+    fn error(&mut self, err: &RuntimeError, depth: usize) {
+        self.line(depth, format!("error: {}", err));
+    }
+}
+
+fn indent(depth: usize) -> String {
+    "  ".repeat(depth)
+}
+
+// Records every event as a JSON object, for example
+// {"event":"enter","name":"fib","args":[2],"depth":0}
+// with "exit" events carrying a "result", "set", "let" and "if" events a
+// "name", "value" or "then", and "error" events a "kind" and "message".
+pub struct JsonTracer {
+    events: Vec<String>
+}
+
+impl JsonTracer {
+    pub fn new() -> Self {
+        JsonTracer {
+            events: Vec::new()
+        }
+    }
+
+    pub fn events(&self) -> &[String] {
+        &self.events
+    }
+
+    // The whole log as one JSON array.
+    pub fn to_json(&self) -> String {
+        format!("[{}]", self.events.join(","))
+    }
+
+    fn event(&mut self, event: &str, fields: String, depth: usize) {
+        self.events.push(format!("{{\"event\":\"{}\",{},\"depth\":{}}}", event, fields, depth));
+    }
+}
+
+impl Tracer for JsonTracer {
+    fn enter(&mut self, name: Symbol, args: &[Value], depth: usize) {
+        let mut fields = name_field(name);
+        fields.push_str(",\"args\":");
+        json_value(&Value::List(args.to_vec()), &mut fields);
+        self.event("enter", fields, depth);
+    }
+
+    fn exit(&mut self, name: Symbol, result: &Value, depth: usize) {
+        let mut fields = name_field(name);
+        fields.push_str(",\"result\":");
+        json_value(result, &mut fields);
+        self.event("exit", fields, depth);
+    }
+
+    fn special_form(&mut self, form: &SpecialForm, depth: usize) {
+        match *form {
+            SpecialForm::Set(name) => self.event("set", name_field(name), depth),
+            SpecialForm::Let(name, val) => {
+                let mut fields = name_field(name);
+                fields.push_str(",\"value\":");
+                json_value(val, &mut fields);
+                self.event("let", fields, depth);
+            },
+            SpecialForm::If(then) => self.event("if", format!("\"then\":{}", then), depth)
+        }
+    }
+
+    fn error(&mut self, err: &RuntimeError, depth: usize) {
+        let mut fields = format!("\"kind\":\"{:?}\",\"message\":", err.kind);
+        json_string(&err.message, &mut fields);
+        self.event("error", fields, depth);
+    }
+}
+
+fn name_field(name: Symbol) -> String {
+    let mut field = "\"name\":".to_string();
+    json_string(name.as_str(), &mut field);
+    field
+}
+
+// Nil is null, strings are strings and lists are arrays. Names and lambdas
+// become {"symbol": name} and {"lambda": [param, ...]} so they can be told
+// from strings.
+fn json_value(val: &Value, out: &mut String) {
+    match *val {
+        Value::Nil => out.push_str("null"),
+        Value::Int(int) => out.push_str(&int.to_string()),
+        Value::Float(float) if float.is_finite() => out.push_str(&format!("{:?}", float)),
+        Value::Float(_) => out.push_str("null"),
+        Value::Str(ref string) => json_string(string, out),
+        Value::Symbol(ref name) => {
+            out.push_str("{\"symbol\":");
+            json_string(name, out);
+            out.push('}');
+        },
+        Value::List(ref items) => {
+            out.push('[');
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                json_value(item, out);
+            }
+            out.push(']');
+        },
+        Value::Lambda(ref func) => {
+            out.push_str("{\"lambda\":[");
+            for (i, param) in func.params.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                json_string(param.as_str(), out);
+            }
+            out.push_str("]}");
+        }
+    }
+}
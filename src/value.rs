@@ -0,0 +1,79 @@
+use eval::Func;
+use std::fmt;
+use std::mem;
+use std::rc::Rc;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Nil,
+    Int(i64),
+    Float(f64),
+    Str(String),
+    Symbol(String),
+    List(Vec<Value>),
+    Lambda(Rc<Func>)
+}
+
+impl Value {
+    // Truth values are the strings (or names) "true" and "false", as
+    // produced by eq.
+    pub fn from_bool(truth: bool) -> Self {
+        Value::Str(match truth {
+            true => "true".to_string(),
+            false => "false".to_string()
+        })
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match *self {
+            Value::Str(ref truth) | Value::Symbol(ref truth) => {
+                match truth.as_str() {
+                    "true" => Some(true),
+                    "false" => Some(false),
+                    _ => None
+                }
+            },
+            _ => None
+        }
+    }
+
+    pub fn is_number(&self) -> bool {
+        match *self {
+            Value::Int(_) | Value::Float(_) => true,
+            _ => false
+        }
+    }
+
+    // Roughly how many bytes the value takes up, counting what it owns.
+    pub fn size(&self) -> usize {
+        mem::size_of::<Value>() + match *self {
+            Value::Str(ref string) | Value::Symbol(ref string) => string.len(),
+            Value::List(ref items) => items.iter().map(Value::size).sum(),
+            Value::Lambda(ref func) => func.size(),
+            _ => 0
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Value::Nil => write!(f, "nil"),
+            Value::Int(int) => write!(f, "{}", int),
+            Value::Float(float) => write!(f, "{}", float),
+            Value::Str(ref string) => write!(f, "{}", string),
+            Value::Symbol(ref name) => write!(f, "{}", name),
+            Value::List(ref items) => {
+                write!(f, "<")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " ")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                write!(f, ">")
+            },
+            Value::Lambda(_) => write!(f, "lambda")
+        }
+    }
+}
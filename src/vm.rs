@@ -0,0 +1,433 @@
+use ast::Ast;
+use compile::{Op, Program};
+use config::InterpreterConfig;
+use eval::{self, Call, RuntimeError, RuntimeErrorKind};
+use expr::Expr;
+use intern::Symbol;
+use token::Span;
+use value::Value;
+
+// What a name is bound to: a value, as params and let give, or a function
+// made by set.
+#[derive(Clone)]
+enum Binding {
+    Value(Value),
+    Func(u32)
+}
+
+struct Frame {
+    proto: u32,
+    ip: usize,
+    // Where the frame's params start in Vm::locals.
+    base: usize,
+    // Where it was called from, None for the program itself.
+    call: Option<Span>,
+    // Names defined while the frame was running that did not exist yet.
+    defs: Vec<(Symbol, Binding)>
+}
+
+// A try being run: the frame it is in, the values below it and where its
+// handler starts.
+struct Handler {
+    frames: usize,
+    values: usize,
+    to: u32
+}
+
+// Runs compiled programs with the same results as Eval, including its
+// dynamic scoping: a name not bound by the running function is looked up
+// in its callers before the globals.
+pub struct Vm {
+    program: Program,
+    globals: Vec<Option<Binding>>,
+    // How many live frames bind each name, so that names no frame binds go
+    // straight to the globals. Both are indexed by Symbol::index.
+    bound: Vec<u32>,
+    frames: Vec<Frame>,
+    locals: Vec<Binding>,
+    values: Vec<Value>,
+    handlers: Vec<Handler>,
+    // Errors caught but not handled yet, for Rethrow.
+    errors: Vec<RuntimeError>,
+    // Set when run_frames stopped with a rethrown error, which already has
+    // its backtrace.
+    rethrown: bool,
+    config: InterpreterConfig
+}
+
+impl Vm {
+    pub fn new() -> Self {
+        Vm {
+            program: Program::new(),
+            globals: Vec::new(),
+            bound: Vec::new(),
+            frames: Vec::new(),
+            locals: Vec::new(),
+            values: Vec::new(),
+            handlers: Vec::new(),
+            errors: Vec::new(),
+            rethrown: false,
+            config: InterpreterConfig::new()
+        }
+    }
+
+    // Only the builtins the config allows can be called. The Vm does not
+    // enforce limits.
+    pub fn set_config(&mut self, config: &InterpreterConfig) {
+        self.config = config.clone();
+    }
+
+    // Compiles and runs a parsed program. Definitions stay for the next run.
+    pub fn run(&mut self, ast: &Ast) -> Result<Value, RuntimeError> {
+        let main = self.program.compile(ast)?;
+        self.execute(main)
+    }
+
+    pub fn run_exprs(&mut self, program: &[Expr]) -> Result<Value, RuntimeError> {
+        let main = self.program.compile_exprs(program);
+        self.execute(main)
+    }
+
+    pub fn program(&self) -> &Program {
+        &self.program
+    }
+
+    fn execute(&mut self, main: u32) -> Result<Value, RuntimeError> {
+        self.frames.push(Frame {
+            proto: main,
+            ip: 0,
+            base: self.locals.len(),
+            call: None,
+            defs: Vec::new()
+        });
+        let result = loop {
+            match self.run_frames() {
+                Ok(val) => break Ok(val),
+                Err(mut err) => {
+                    if !self.rethrown {
+                        let calls = self.frames.iter().filter_map(|frame| frame.call.map(|span| Call {
+                            name: self.program.protos[frame.proto as usize].name,
+                            span: span,
+                            builtin: false
+                        }));
+                        err.backtrace.splice(0..0, calls);
+                    }
+                    self.rethrown = false;
+                    match err.kind.is_catchable() && !self.handlers.is_empty() {
+                        true => self.catch(err),
+                        false => break Err(err)
+                    }
+                }
+            }
+        };
+        if result.is_err() {
+            self.frames.clear();
+            self.locals.clear();
+            self.values.clear();
+            self.handlers.clear();
+            self.errors.clear();
+            for count in self.bound.iter_mut() {
+                *count = 0;
+            }
+        }
+        result
+    }
+
+    // Unwinds to the innermost try and jumps to its handler.
+    fn catch(&mut self, err: RuntimeError) {
+        let handler = self.handlers.pop().unwrap();
+        while self.frames.len() > handler.frames {
+            self.pop_frame();
+        }
+        self.values.truncate(handler.values);
+        self.values.push(err.to_value());
+        self.errors.push(err);
+        self.frames.last_mut().unwrap().ip = handler.to as usize;
+    }
+
+    fn pop_frame(&mut self) -> Frame {
+        let frame = self.frames.pop().unwrap();
+        for param in self.program.protos[frame.proto as usize].params.iter() {
+            self.bound[param.index()] -= 1;
+        }
+        for def in frame.defs.iter() {
+            self.bound[def.0.index()] -= 1;
+        }
+        self.locals.truncate(frame.base);
+        frame
+    }
+
+    fn run_frames(&mut self) -> Result<Value, RuntimeError> {
+        loop {
+            let (proto, base, ip) = {
+                let frame = self.frames.last_mut().unwrap();
+                frame.ip += 1;
+                (frame.proto as usize, frame.base, frame.ip - 1)
+            };
+            match self.program.protos[proto].code[ip] {
+                Op::Const(index) => self.values.push(self.program.protos[proto].consts[index as usize].clone()),
+                Op::Symbol(name) => self.values.push(Value::Symbol(name.as_str().to_string())),
+                Op::Nil => self.values.push(Value::Nil),
+                Op::Pop => {
+                    self.values.pop();
+                },
+                Op::MakeList(len) => {
+                    let at = self.values.len() - len as usize;
+                    let items = self.values.split_off(at);
+                    self.values.push(Value::List(items));
+                },
+                Op::Branch { to, form, at } => {
+                    let cond = self.values.pop().unwrap();
+                    match cond.as_bool() {
+                        Some(true) => (),
+                        Some(false) => self.frames.last_mut().unwrap().ip = to as usize,
+                        None => {
+                            let message = format!("`{}` condition must be \"true\" or \"false\", found `{}`", form, cond);
+                            return Err(RuntimeError::new(RuntimeErrorKind::InvalidCondition, message, self.span(proto, at)));
+                        }
+                    }
+                },
+                Op::Jump(to) => self.frames.last_mut().unwrap().ip = to as usize,
+                Op::CallLocal { slot, argc, at } => {
+                    let binding = self.locals[base + slot as usize].clone();
+                    let name = self.program.protos[proto].params[slot as usize];
+                    self.call_binding(binding, name, argc as usize, self.span(proto, at))?;
+                },
+                Op::CallName { name, argc, at } => {
+                    let span = self.span(proto, at);
+                    match self.lookup(name) {
+                        Some(binding) => self.call_binding(binding, name, argc as usize, span)?,
+                        None => self.call_builtin(name, argc as usize, span)?
+                    }
+                },
+                Op::CallValue { argc, at } => {
+                    let span = self.span(proto, at);
+                    let index = self.values.len() - argc as usize - 1;
+                    match self.values.remove(index) {
+                        Value::Lambda(func) => {
+                            let callee = self.program.lambda_proto(&func);
+                            self.call_proto(callee, argc as usize, span)?;
+                        },
+                        other => {
+                            let message = format!("`{}` can not be called", other);
+                            return Err(RuntimeError::new(RuntimeErrorKind::NotCallable, message, span));
+                        }
+                    }
+                },
+                Op::SetLocal { slot, proto } => {
+                    self.locals[base + slot as usize] = Binding::Func(proto);
+                    self.values.push(Value::Nil);
+                },
+                Op::SetName { name, proto } => {
+                    self.define(name, Binding::Func(proto));
+                    self.values.push(Value::Nil);
+                },
+                Op::BindLocal(slot) => {
+                    let val = self.values.pop().unwrap();
+                    self.locals[base + slot as usize] = Binding::Value(val);
+                    self.values.push(Value::Nil);
+                },
+                Op::BindName(name) => {
+                    let val = self.values.pop().unwrap();
+                    self.define(name, Binding::Value(val));
+                    self.values.push(Value::Nil);
+                },
+                Op::CallProto { proto: callee, argc, at } => {
+                    let span = self.span(proto, at);
+                    self.call_proto(callee, argc as usize, span)?;
+                },
+                Op::Try(to) => self.handlers.push(Handler {
+                    frames: self.frames.len(),
+                    values: self.values.len(),
+                    to: to
+                }),
+                Op::EndTry => {
+                    self.handlers.pop();
+                },
+                Op::Forget => {
+                    self.errors.pop();
+                },
+                Op::Rethrow => {
+                    self.rethrown = true;
+                    return Err(self.errors.pop().unwrap());
+                },
+                Op::Return => {
+                    self.pop_frame();
+                    if self.frames.is_empty() {
+                        return Ok(self.values.pop().unwrap_or(Value::Nil));
+                    }
+                }
+            }
+        }
+    }
+
+    fn span(&self, proto: usize, at: u32) -> Option<Span> {
+        let span = self.program.protos[proto].spans[at as usize];
+        match span.is_dummy() {
+            true => None,
+            false => Some(span)
+        }
+    }
+
+    fn grow(&mut self, name: Symbol) {
+        if self.bound.len() <= name.index() {
+            self.bound.resize(name.index() + 1, 0);
+            self.globals.resize(name.index() + 1, None);
+        }
+    }
+
+    fn lookup(&mut self, name: Symbol) -> Option<Binding> {
+        self.grow(name);
+        if self.bound[name.index()] > 0 {
+            for frame in self.frames.iter().rev() {
+                let params = &self.program.protos[frame.proto as usize].params;
+                if let Some(slot) = params.iter().position(|param| *param == name) {
+                    return Some(self.locals[frame.base + slot].clone());
+                }
+                if let Some(def) = frame.defs.iter().find(|def| def.0 == name) {
+                    return Some(def.1.clone());
+                }
+            }
+        }
+        self.globals[name.index()].clone()
+    }
+
+    // Rebinds the nearest existing binding of name, or adds it to the
+    // running function, or to the globals at the top level.
+    fn define(&mut self, name: Symbol, binding: Binding) {
+        self.grow(name);
+        if self.bound[name.index()] > 0 {
+            for i in (0..self.frames.len()).rev() {
+                let base = self.frames[i].base;
+                let params = &self.program.protos[self.frames[i].proto as usize].params;
+                if let Some(slot) = params.iter().position(|param| *param == name) {
+                    self.locals[base + slot] = binding;
+                    return;
+                }
+                if let Some(def) = self.frames[i].defs.iter_mut().find(|def| def.0 == name) {
+                    def.1 = binding;
+                    return;
+                }
+            }
+        }
+        if self.globals[name.index()].is_some() || self.frames.len() <= 1 {
+            self.globals[name.index()] = Some(binding);
+        } else {
+            self.frames.last_mut().unwrap().defs.push((name, binding));
+            self.bound[name.index()] += 1;
+        }
+    }
+
+    fn call_binding(&mut self, binding: Binding, name: Symbol, argc: usize, span: Option<Span>) -> Result<(), RuntimeError> {
+        match binding {
+            Binding::Func(proto) => self.call_proto(proto, argc, span),
+            Binding::Value(val) => {
+                match (val, argc) {
+                    (val, 0) => {
+                        self.values.push(val);
+                        Ok(())
+                    },
+                    // A let bound to a lambda is called through its value.
+                    (Value::Lambda(func), _) => {
+                        let proto = self.program.lambda_proto(&func);
+                        self.call_proto(proto, argc, span)
+                    },
+                    _ => {
+                        let message = format!("`{}` expects 0 argument(s), found {}", name, argc);
+                        Err(RuntimeError::new(RuntimeErrorKind::Arity, message, span))
+                    }
+                }
+            }
+        }
+    }
+
+    fn call_proto(&mut self, proto: u32, argc: usize, span: Option<Span>) -> Result<(), RuntimeError> {
+        let callee = &self.program.protos[proto as usize];
+        if callee.params.len() != argc {
+            let message = format!("`{}` expects {} argument(s), found {}", callee.name, callee.params.len(), argc);
+            return Err(RuntimeError::new(RuntimeErrorKind::Arity, message, span));
+        }
+        for i in 0..argc {
+            let param = self.program.protos[proto as usize].params[i];
+            self.grow(param);
+            self.bound[param.index()] += 1;
+        }
+        let base = self.locals.len();
+        let at = self.values.len() - argc;
+        self.locals.extend(self.values.drain(at..).map(Binding::Value));
+        self.frames.push(Frame {
+            proto: proto,
+            ip: 0,
+            base: base,
+            call: Some(span.unwrap_or_default()),
+            defs: Vec::new()
+        });
+        Ok(())
+    }
+
+    fn call_builtin(&mut self, name: Symbol, argc: usize, span: Option<Span>) -> Result<(), RuntimeError> {
+        let at = self.values.len() - argc;
+        let result = self.config.call_builtin(name.as_str(), &self.values[at..]);
+        self.values.truncate(at);
+        match result {
+            Some(Ok(val)) => {
+                self.values.push(val);
+                Ok(())
+            },
+            Some(Err(err)) => {
+                let call = Call {
+                    name: name,
+                    span: span.unwrap_or_default(),
+                    builtin: true
+                };
+                Err(eval::builtin_error(err, span).with_backtrace(vec![call]))
+            },
+            None => {
+                let message = format!("unknown function `{}`", name);
+                Err(RuntimeError::new(RuntimeErrorKind::UnknownFunction, message, span))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use eval::{Eval, Scope};
+    use lex::Lexer;
+    use parse::{Parser, ParserTrait};
+
+    fn parse(src: &str) -> Ast {
+        let mut parser = Parser::new();
+        for tok in Lexer::new(src) {
+            parser.parse_token(tok.unwrap()).unwrap();
+        }
+        parser.finish().unwrap()
+    }
+
+    // Eval and the Vm fail in the same way, at the same place.
+    fn both_fail(src: &str) -> RuntimeError {
+        let tree = parse(src);
+        let eval = Eval::new(tree.clone()).eval_with_scope(Scope::new_root()).1.unwrap_err();
+        let vm = Vm::new().run(&tree).unwrap_err();
+        assert_eq!((&eval.kind, &eval.message, eval.span), (&vm.kind, &vm.message, vm.span), "{}", src);
+        eval
+    }
+
+    #[test]
+    fn condition_errors_name_the_form_and_point_at_the_condition() {
+        let cases = [
+            ("(if \"x\" 1 2)", "if", "\"x\""),
+            ("(when (add 1 2) 1)", "when", "(add 1 2)"),
+            ("(unless 3 1)", "unless", "3"),
+            ("(cond (false 1)\n  ((add 1 1) 2))", "cond", "(add 1 1)")
+        ];
+        for &(src, form, cond) in cases.iter() {
+            let err = both_fail(src);
+            assert_eq!(err.kind, RuntimeErrorKind::InvalidCondition);
+            assert!(err.message.starts_with(&format!("`{}` condition", form)), "{}", err.message);
+            let span = err.span.expect(src);
+            assert_eq!(&src[span.start..span.end], cond, "{}", src);
+        }
+    }
+}